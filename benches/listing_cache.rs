@@ -0,0 +1,40 @@
+//! How long a `madison-web` update takes when no listing has changed, with and without
+//! `cache_listings`.  Uses the mirrors from Rocket.toml, so needs network access for the first
+//! download.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use madison_rs::{init_system, key_func, ListingCache, MadisonConfig};
+
+fn no_op_update(c: &mut Criterion) {
+    let config: MadisonConfig = rocket::Config::figment().extract().expect("config");
+    let system = tokio::runtime::Runtime::new()
+        .expect("runtime")
+        .block_on(init_system(&config))
+        .expect("system");
+
+    let mut group = c.benchmark_group("no-op update");
+    group.sample_size(10);
+    group.bench_function("uncached", |b| {
+        b.iter(|| {
+            ListingCache::new()
+                .build(&system, &key_func::codename, &config, None)
+                .expect("mapping")
+        })
+    });
+    let cache = ListingCache::new();
+    cache
+        .build(&system, &key_func::codename, &config, None)
+        .expect("mapping");
+    group.bench_function("cached", |b| {
+        b.iter(|| {
+            cache
+                .build(&system, &key_func::codename, &config, None)
+                .expect("mapping")
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, no_op_update);
+criterion_main!(benches);
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+/// Convert deb822-style `.sources` content into the equivalent one-line `deb` entries, which is
+/// the only form `fapt::sources_list::read` understands.
+///
+/// Each stanza becomes an entry for every combination of its `Types`, `URIs` and `Suites`, with
+/// its `Architectures` carried over as an option, and `Trusted: yes` as fapt's `untrusted=yes`.
+/// Stanzas with `Enabled: no` are skipped; other fields (e.g. `Signed-By`) are ignored.
+pub(crate) fn to_one_line(content: &str) -> Result<String, anyhow::Error> {
+    let mut lines = String::new();
+    for stanza in stanzas(content)? {
+        let field = |name: &str| -> Vec<&str> {
+            stanza
+                .get(&name.to_ascii_lowercase())
+                .map(|value| value.split_whitespace().collect())
+                .unwrap_or_default()
+        };
+        if field("Enabled")
+            .first()
+            .is_some_and(|e| e.eq_ignore_ascii_case("no"))
+        {
+            continue;
+        }
+        let (types, uris, suites) = (field("Types"), field("URIs"), field("Suites"));
+        if types.is_empty() || uris.is_empty() || suites.is_empty() {
+            anyhow::bail!(
+                "a .sources stanza needs Types, URIs and Suites: {:?}",
+                stanza
+            );
+        }
+        let components = field("Components");
+        let mut options = vec![];
+        let arches = field("Architectures");
+        if !arches.is_empty() {
+            options.push(format!("arch={}", arches.join(",")));
+        }
+        // fapt's spelling of apt's trusted=yes: the Release file's signature isn't checked
+        if field("Trusted")
+            .first()
+            .is_some_and(|t| t.eq_ignore_ascii_case("yes"))
+        {
+            options.push("untrusted=yes".to_string());
+        }
+        let options = if options.is_empty() {
+            String::new()
+        } else {
+            format!("[{}] ", options.join(" "))
+        };
+        for kind in &types {
+            for uri in &uris {
+                for suite in &suites {
+                    // A suite ending in '/' is a flat repository, which takes no components
+                    let components = if suite.ends_with('/') {
+                        String::new()
+                    } else {
+                        format!(" {}", components.join(" "))
+                    };
+                    lines.push_str(&format!(
+                        "{} {}{} {}{}\n",
+                        kind, options, uri, suite, components
+                    ));
+                }
+            }
+        }
+    }
+    Ok(lines)
+}
+
+/// The stanzas of `content`, as maps from lowercased field names to their (unfolded) values.
+fn stanzas(content: &str) -> Result<Vec<HashMap<String, String>>, anyhow::Error> {
+    let mut stanzas = vec![];
+    let mut stanza = HashMap::new();
+    let mut last_field: Option<String> = None;
+    for line in content.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.trim().is_empty() {
+            if !stanza.is_empty() {
+                stanzas.push(std::mem::take(&mut stanza));
+            }
+            last_field = None;
+        } else if line.starts_with(char::is_whitespace) {
+            // A continuation of the previous field's value
+            let value = last_field
+                .as_ref()
+                .and_then(|name| stanza.get_mut(name))
+                .ok_or_else(|| anyhow::anyhow!("continuation line outside a field: {:?}", line))?;
+            value.push(' ');
+            value.push_str(line.trim());
+        } else {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("malformed .sources line: {:?}", line))?;
+            let name = name.trim().to_ascii_lowercase();
+            stanza.insert(name.clone(), value.trim().to_string());
+            last_field = Some(name);
+        }
+    }
+    if !stanza.is_empty() {
+        stanzas.push(stanza);
+    }
+    Ok(stanzas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_suite_stanzas_become_an_entry_per_suite() {
+        let sources = "\
+# The main archive
+Types: deb deb-src
+URIs: http://deb.debian.org/debian
+Suites: bookworm bookworm-updates
+Components: main contrib
+Architectures: amd64 arm64
+Signed-By: /usr/share/keyrings/debian-archive-keyring.gpg
+
+Types: deb
+URIs: http://host/flat
+Suites: ./
+Trusted: yes
+
+Types: deb
+URIs: http://deb.debian.org/debian
+Suites: sid
+Components: main
+Enabled: no
+";
+        let one_line = to_one_line(sources).expect("valid .sources");
+        assert_eq!(
+            one_line,
+            "\
+deb [arch=amd64,arm64] http://deb.debian.org/debian bookworm main contrib
+deb [arch=amd64,arm64] http://deb.debian.org/debian bookworm-updates main contrib
+deb-src [arch=amd64,arm64] http://deb.debian.org/debian bookworm main contrib
+deb-src [arch=amd64,arm64] http://deb.debian.org/debian bookworm-updates main contrib
+deb [untrusted=yes] http://host/flat ./
+"
+        );
+        // Every entry is one fapt can read
+        let entries = fapt::sources_list::read(one_line.as_bytes()).expect("valid sources list");
+        let suites: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.suite_codename.as_str())
+            .collect();
+        assert_eq!(
+            suites,
+            [
+                "bookworm",
+                "bookworm-updates",
+                "bookworm",
+                "bookworm-updates",
+                "./"
+            ]
+        );
+    }
+
+    #[test]
+    fn stanzas_need_types_uris_and_suites() {
+        assert!(to_one_line("Types: deb\nURIs: http://deb.debian.org/debian\n").is_err());
+    }
+}
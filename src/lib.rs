@@ -2,15 +2,22 @@
 extern crate rocket;
 
 use std::cmp::Ordering;
-use std::collections::hash_map::Entry;
+use std::collections::hash_map::{DefaultHasher, Entry};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs::File;
-use std::io::BufReader;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, Cursor, Read};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use flate2::read::GzDecoder;
 
 use fapt::commands;
+use fapt::packages::{Package, Priority};
 use fapt::sources_list;
-use fapt::system::System;
+use fapt::system::{DownloadedList, System};
 
 use tabled::{builder::Builder, settings::Style};
 
@@ -18,27 +25,423 @@ use serde::{Deserialize, Serialize};
 
 use rayon::prelude::*;
 
+use regex::RegexBuilder;
+
+use version::VersionComparator;
+
+mod deb822;
 pub mod madison_web;
+mod metrics;
 
-pub type MadisonMapping = HashMap<String, HashMap<(String, String), HashSet<String>>>;
+pub type MadisonMapping = HashMap<String, HashMap<(String, String), MadisonEntry>>;
 pub type MadisonStructure = HashMap<String, Vec<MadisonOutputRecord>>;
 
-#[derive(Deserialize)]
+/// The architecture of architecture-independent binaries.
+pub const ARCH_ALL: &str = "all";
+
+/// One of the types a package is available as: its source, or a binary architecture.
+///
+/// Deriving `Ord` sorts `Source` before every architecture, and architectures by name.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PackageType {
+    Source,
+    Arch(String),
+}
+
+impl std::fmt::Display for PackageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackageType::Source => write!(f, "source"),
+            PackageType::Arch(arch) => write!(f, "{}", arch),
+        }
+    }
+}
+
+/// Everything recorded about a package at one version in one suite.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MadisonEntry {
+    pub types: HashSet<PackageType>,
+    pub priority: Option<String>,
+    pub essential: bool,
+    /// For source packages, the names of the binaries built from this source.
+    pub binaries: HashSet<String>,
+    /// The source packages named in this binary's `Built-Using` field.
+    pub built_using: HashSet<String>,
+    /// This binary's `Multi-Arch` field (`same`, `foreign` or `allowed`).
+    pub multi_arch: Option<String>,
+    /// The debtags in this binary's `Tag` field, e.g. `role::program`.
+    pub tags: HashSet<String>,
+    /// This binary's `Size` (of its .deb) and `Installed-Size` (in KiB) fields; the largest
+    /// across architectures once merged.
+    pub size: Option<u64>,
+    pub installed_size: Option<u64>,
+}
+
+impl MadisonEntry {
+    fn merge(&mut self, other: MadisonEntry) {
+        self.types.extend(other.types);
+        self.binaries.extend(other.binaries);
+        self.built_using.extend(other.built_using);
+        self.tags.extend(other.tags);
+        self.priority = self.priority.take().or(other.priority);
+        self.essential |= other.essential;
+        self.multi_arch = self.multi_arch.take().or(other.multi_arch);
+        self.size = self.size.max(other.size);
+        self.installed_size = self.installed_size.max(other.installed_size);
+    }
+}
+
+/// Restrictions applied to the rows produced by `generate_madison_structure`.
+#[derive(Default)]
+pub struct MadisonFilter {
+    /// Only include rows from these suites (matched case-insensitively); `None` includes every
+    /// suite.
+    pub suites: Option<HashSet<String>>,
+    pub priority: Option<String>,
+    pub essential: bool,
+    /// Only include binaries whose `Built-Using` names this source package
+    pub built_using: Option<String>,
+    /// Only include binaries with this `Multi-Arch` value
+    pub multi_arch: Option<String>,
+    /// Only include versions starting with this, e.g. `2.` for any 2.x version
+    pub version_prefix: Option<String>,
+    /// Only include versions satisfying this, e.g. `>= 2.0`
+    pub version_constraint: Option<version::Constraint>,
+    /// Leave out versions containing `~`, which sort before the release they lead up to: release
+    /// candidates like `2.0~rc1` and backports like `2.0-1~bpo12+1`
+    pub no_prerelease: bool,
+    /// Only include binaries carrying this debtag
+    pub tag: Option<String>,
+    /// Only show these architectures (including `source`) in each row, dropping rows which have
+    /// none of them; `None` shows every architecture.
+    pub arches: Option<HashSet<String>>,
+    /// Treat queries as source package names only: rows are only for source packages, showing the
+    /// architectures of their binaries (requires `include_source_arch`)
+    pub source_only: bool,
+    /// Packages which get a "not found" placeholder row, rather than no rows, when absent
+    pub watched_packages: HashSet<String>,
+    /// Names which `suites` may use for a codename, e.g. `stable` for `bookworm`
+    pub suite_aliases: HashMap<String, String>,
+}
+
+impl MadisonFilter {
+    /// A filter for the suites in `suite`, which may be a comma-separated list.
+    pub fn with_suite(suite: Option<String>) -> Self {
+        let mut filter = MadisonFilter::default();
+        if let Some(suite) = suite {
+            filter.add_suites(&suite);
+        }
+        filter
+    }
+
+    /// Additionally show each of the comma-separated `arches`, e.g. `amd64,arm64`.
+    pub fn add_arches(&mut self, arches: &str) {
+        self.arches.get_or_insert_with(HashSet::new).extend(
+            arches
+                .split(',')
+                .map(str::trim)
+                .filter(|arch| !arch.is_empty())
+                .map(str::to_string),
+        );
+    }
+
+    /// Strip dpkg-style architecture qualifiers from `packages` (so `foo:arm64` is `foo`), showing
+    /// the qualifying architectures.  `:any` and `:native` qualifiers are stripped without
+    /// restricting architectures.  Like the other filters, the architectures apply to every
+    /// package queried alongside.
+    pub fn take_arch_qualifiers(&mut self, packages: Vec<String>) -> Vec<String> {
+        packages
+            .into_iter()
+            .map(|package| match package.split_once(':') {
+                Some((name, arch)) => {
+                    if !matches!(arch, "any" | "native") {
+                        self.add_arches(arch);
+                    }
+                    name.to_string()
+                }
+                None => package,
+            })
+            .collect()
+    }
+
+    /// Additionally allow each of the comma-separated `suites`, e.g. `bookworm,trixie`.
+    pub fn add_suites(&mut self, suites: &str) {
+        self.suites.get_or_insert_with(HashSet::new).extend(
+            suites
+                .split(',')
+                .map(str::trim)
+                .filter(|suite| !suite.is_empty())
+                .map(str::to_string),
+        );
+    }
+
+    fn matches(
+        &self,
+        codename: &str,
+        version: &str,
+        entry: &MadisonEntry,
+        comparator: &dyn VersionComparator,
+    ) -> bool {
+        self.suites
+            .as_ref()
+            .map(|suites| {
+                // A suite also matches keys of its components, like `bookworm/contrib`
+                let suite_part = codename.split_once('/').map(|(suite, _)| suite);
+                suites.iter().any(|suite| {
+                    let suite = suite.trim();
+                    // Anything which isn't an alias is taken to be a codename; like codenames,
+                    // aliases are matched case-insensitively
+                    let suite = self
+                        .suite_aliases
+                        .iter()
+                        .find(|(alias, _)| alias.eq_ignore_ascii_case(suite))
+                        .map(|(_, codename)| codename.as_str())
+                        .unwrap_or(suite);
+                    suite.eq_ignore_ascii_case(codename)
+                        || suite_part
+                            .map(|suite_part| suite.eq_ignore_ascii_case(suite_part))
+                            .unwrap_or(false)
+                })
+            })
+            .unwrap_or(true)
+            && self
+                .priority
+                .as_ref()
+                .map(|priority| entry.priority.as_ref() == Some(priority))
+                .unwrap_or(true)
+            && (!self.essential || entry.essential)
+            && self
+                .built_using
+                .as_ref()
+                .map(|source| entry.built_using.contains(source))
+                .unwrap_or(true)
+            && self
+                .multi_arch
+                .as_ref()
+                .map(|multi_arch| entry.multi_arch.as_ref() == Some(multi_arch))
+                .unwrap_or(true)
+            && self
+                .version_prefix
+                .as_ref()
+                .map(|prefix| version_has_prefix(version, prefix))
+                .unwrap_or(true)
+            && self
+                .version_constraint
+                .as_ref()
+                .map(|constraint| constraint.satisfied_by(version, comparator))
+                .unwrap_or(true)
+            && !(self.no_prerelease && version.contains('~'))
+            && (!self.source_only || entry.types.contains(&PackageType::Source))
+            && self
+                .tag
+                .as_ref()
+                .map(|tag| entry.tags.contains(tag))
+                .unwrap_or(true)
+    }
+}
+
+/// Whether `version` starts with `prefix`, ignoring `version`'s epoch unless `prefix` gives one.
+fn version_has_prefix(version: &str, prefix: &str) -> bool {
+    let version = version.trim();
+    let prefix = prefix.trim();
+    if prefix.contains(':') {
+        return version.starts_with(prefix);
+    }
+    let without_epoch = match version.split_once(':') {
+        Some((_, upstream)) => upstream,
+        None => version,
+    };
+    without_epoch.starts_with(prefix)
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct MadisonConfig {
     pub sources_list: String,
     pub extra_key_paths: Vec<String>,
     pub arches: Vec<String>,
     pub include_source_arch: bool,
+    /// Whether binary package names are keys of the mapping; if not, only source package names
+    /// can be looked up
+    #[serde(default = "default_index_binaries")]
+    pub index_binaries: bool,
+    /// Architectures whose listings are skipped entirely when building the mapping
+    #[serde(default)]
+    pub exclude_arches: Vec<String>,
+    /// Whether queried names like `foo:amd64` are looked up as `foo`, showing only `amd64`
+    #[serde(default = "default_strip_arch_qualifiers")]
+    pub strip_arch_qualifiers: bool,
+    /// If set, only listings of these components (e.g. `main`) are included in the mapping
+    pub components: Option<Vec<String>>,
     // TODO: This is madison-web specific
     pub enable_metrics: bool,
+    pub bind_address: Option<String>,
+    #[serde(default)]
+    pub eol_suites: Vec<String>,
+    /// Names accepted in place of codenames when filtering by suite, e.g. `stable = "bookworm"`
+    #[serde(default)]
+    pub suite_aliases: HashMap<String, String>,
+    #[serde(default)]
+    pub enable_package_metrics: bool,
+    pub max_package_metrics: Option<usize>,
+    #[serde(default)]
+    pub enable_export: bool,
+    /// Serve every tracked package, in madison's text format, at `/all`
+    #[serde(default)]
+    pub enable_all_report: bool,
+    /// A package which madison-web's `/selftest` looks up, failing unless it's found (in
+    /// `selftest_suite`, if set)
+    pub selftest_package: Option<String>,
+    pub selftest_suite: Option<String>,
+    /// Whether madison-web keeps each listing's parsed rows between rebuilds, to skip re-parsing
+    /// unchanged listings at the cost of memory
+    #[serde(default)]
+    pub cache_listings: bool,
+    /// A file in which madison-web keeps a history of the versions it has seen, served at
+    /// `/history`
+    pub history_db: Option<String>,
+    /// How long history is kept for (default 7 days)
+    pub history_retention_days: Option<u64>,
+    #[serde(default)]
+    pub enable_packages_index: bool,
+    /// The largest request body accepted by madison-web's `POST /lookup`
+    pub max_request_body_bytes: Option<u64>,
+    /// Additional key functions (by `key_func::by_name` name) to build mappings for, so
+    /// madison-web requests can select them with `&by=`
+    #[serde(default)]
+    pub groupings: Vec<String>,
+    /// Packages which always produce a row, even when they aren't found
+    #[serde(default)]
+    pub watched_packages: HashSet<String>,
+    /// Codenames in the order new versions flow through them, e.g. `["sid", "trixie", "bookworm"]`
+    #[serde(default)]
+    pub suite_order: Vec<String>,
+    /// Limits on how long the initial archive fetch, and each periodic update, may take
+    pub initial_update_timeout_secs: Option<u64>,
+    pub periodic_update_timeout_secs: Option<u64>,
+    /// The longest wait before retrying a failed periodic update (retries start after 1 second,
+    /// doubling each time); defaults to the update interval
+    pub max_update_backoff_secs: Option<u64>,
+    /// For each mirror URL in the sources list, the mirrors to fetch its entries from instead,
+    /// in turn, when a periodic update fails
+    #[serde(default)]
+    pub fallback_mirrors: HashMap<String, Vec<String>>,
+    /// How often madison-web updates from the archive (default 60 seconds); 0 disables periodic
+    /// updates, so only a `SIGHUP` refreshes the mapping
+    pub update_interval_secs: Option<u64>,
+    /// Up to how much longer than usual to wait between periodic updates, chosen at random each
+    /// time, so that instances sharing a mirror don't all hit it at once
+    pub update_jitter_secs: Option<u64>,
+    /// Whether to warn when a suite comes from more than one mirror (whose listings are merged,
+    /// keeping the newest version of each package)
+    #[serde(default)]
+    pub warn_conflicting_mirrors: bool,
+    /// Paths to a client certificate and key for mirrors requiring mutual TLS
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    /// The suite that packages from flat repositories (`deb http://host/ ./`) are listed under
+    pub flat_suite_name: Option<String>,
+    /// A name for this madison-web instance, shown in its pages and metrics
+    pub instance_name: Option<String>,
+    /// Whether to leave suites whose Release file is past its `Valid-Until` out of the mapping
+    #[serde(default)]
+    pub refuse_expired_suites: bool,
+    /// The longest regex madison-web accepts in `r=on` queries
+    pub max_regex_length: Option<usize>,
+    /// The most packages a madison-web regex query may match; more gets a 400
+    pub max_regex_matches: Option<usize>,
+    /// The `key_func::by_name` key function which groups rows (and so fills the codename column),
+    /// overriding the binary's default of `codename`
+    pub key: Option<String>,
+    /// The bearer token required by madison-web's admin routes, which are only served when set
+    pub admin_token: Option<String>,
+    /// How versions are ordered, by `version::by_name` name (default `"debian"`)
+    pub version_comparator: Option<String>,
+}
+
+impl MadisonConfig {
+    /// The configured `version_comparator`.
+    pub fn version_comparator(&self) -> Result<&'static dyn VersionComparator, anyhow::Error> {
+        match &self.version_comparator {
+            Some(name) => version::by_name(name)
+                .ok_or_else(|| anyhow::anyhow!("unknown version_comparator {:?}", name)),
+            None => Ok(&version::Debian),
+        }
+    }
+
+    /// Whether listings of `component` are included, per `components`.
+    pub fn includes_component(&self, component: &str) -> bool {
+        self.components
+            .as_ref()
+            .map(|components| components.iter().any(|included| included == component))
+            .unwrap_or(true)
+    }
+
+    /// The configured `arches`, less `exclude_arches`.
+    pub fn tracked_arches(&self) -> Vec<String> {
+        self.arches
+            .iter()
+            .filter(|arch| !self.exclude_arches.contains(arch))
+            .cloned()
+            .collect()
+    }
+}
+
+fn default_index_binaries() -> bool {
+    true
+}
+
+fn default_strip_arch_qualifiers() -> bool {
+    true
 }
 
-#[derive(Serialize)]
+const DEFAULT_FLAT_SUITE_NAME: &str = "flat";
+
+/// Whether `list` comes from a flat repository, whose sources.list "suite" is a path (e.g. `./`)
+/// rather than a directory under `dists/`.
+fn is_flat(list: &DownloadedList) -> bool {
+    is_flat_suite(&list.release.req.codename)
+}
+
+fn is_flat_suite(suite: &str) -> bool {
+    suite.ends_with('/')
+}
+
+/// The key for a flat repository's listings.
+fn flat_key(config: &MadisonConfig) -> String {
+    config
+        .flat_suite_name
+        .clone()
+        .unwrap_or_else(|| DEFAULT_FLAT_SUITE_NAME.to_string())
+}
+
+/// The key `list`'s packages are recorded under.  Flat repositories have neither a real codename
+/// nor components, so they are all keyed under the configured `flat_suite_name` instead.
+fn listing_key(
+    list: &DownloadedList,
+    key_func: &key_func::KeyFunc,
+    config: &MadisonConfig,
+) -> String {
+    if is_flat(list) {
+        return flat_key(config);
+    }
+    key_func(list)
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct MadisonOutputRecord {
     pub package: String,
     pub version: String,
     pub codename: String,
     pub architectures: String,
+    /// The number of distinct binaries built from this source, for source package rows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binary_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multi_arch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(rename = "installed-size", skip_serializing_if = "Option::is_none")]
+    pub installed_size: Option<u64>,
 }
 
 impl MadisonOutputRecord {
@@ -48,8 +451,97 @@ impl MadisonOutputRecord {
             version,
             codename,
             architectures,
+            binary_count: None,
+            multi_arch: None,
+            size: None,
+            installed_size: None,
+        }
+    }
+
+    pub fn column(&self, column: Column) -> String {
+        let size = |size: Option<u64>| size.map(|size| size.to_string()).unwrap_or_default();
+        match column {
+            Column::Package => self.package.clone(),
+            Column::Version => self.version.clone(),
+            Column::Codename => self.codename.clone(),
+            Column::Architectures => self.architectures.clone(),
+            Column::Size => size(self.size),
+            Column::InstalledSize => size(self.installed_size),
+        }
+    }
+
+    /// The values of `columns`, in that order.
+    pub fn project(&self, columns: &[Column]) -> Vec<String> {
+        columns.iter().map(|column| self.column(*column)).collect()
+    }
+}
+
+/// A column of madison output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    Package,
+    Version,
+    Codename,
+    Architectures,
+    /// The `Size` of the row's .deb, in bytes
+    Size,
+    /// The `Installed-Size` of the row's binary, in KiB
+    InstalledSize,
+}
+
+impl Column {
+    /// The rmadison column order.
+    pub const DEFAULT: &'static [Column] = &[
+        Column::Package,
+        Column::Version,
+        Column::Codename,
+        Column::Architectures,
+    ];
+
+    pub const ALL: &'static [Column] = &[
+        Column::Package,
+        Column::Version,
+        Column::Codename,
+        Column::Architectures,
+        Column::Size,
+        Column::InstalledSize,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Package => "package",
+            Column::Version => "version",
+            Column::Codename => "codename",
+            Column::Architectures => "architectures",
+            Column::Size => "size",
+            Column::InstalledSize => "installed-size",
         }
     }
+
+    /// Parse a comma-separated list of column names, e.g. `codename,package,version`.
+    pub fn parse_list(columns: &str) -> Result<Vec<Column>, anyhow::Error> {
+        columns
+            .split(',')
+            .map(|column| column.trim().parse())
+            .collect()
+    }
+}
+
+impl FromStr for Column {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Column::ALL
+            .iter()
+            .find(|column| column.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "unknown column {:?} (expected one of: package, version, codename, architectures, size, installed-size)",
+                    s
+                )
+            })
+    }
 }
 
 impl IntoIterator for MadisonOutputRecord {
@@ -66,213 +558,4235 @@ impl IntoIterator for MadisonOutputRecord {
     }
 }
 
-pub async fn init_system(config: &MadisonConfig) -> Result<System, anyhow::Error> {
-    // Setup the system
-    let mut system = System::cache_only()?;
-    for path in &config.extra_key_paths {
-        system.add_keys_from(File::open(path)?)?;
+/// Read a key file, transparently decompressing it if it's gzipped (e.g. `keyring.gpg.gz`).
+fn read_key_file(path: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    if contents.starts_with(&[0x1f, 0x8b]) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&contents[..]).read_to_end(&mut decompressed)?;
+        contents = decompressed;
     }
+    Ok(contents)
+}
+
+/// Create a `System`, refusing configuration it can't honour.
+fn new_system(config: &MadisonConfig) -> Result<System, anyhow::Error> {
+    // fapt's System creates its own HTTP client, with no way to supply a client identity
+    if config.client_cert.is_some() || config.client_key.is_some() {
+        anyhow::bail!(
+            "client_cert/client_key are configured, but fapt does not support TLS client \
+             certificates, so mutual TLS mirrors cannot be used"
+        );
+    }
+    Ok(System::cache_only()?)
+}
+
+/// Add the builtin keys and the given sources to `system` (with their mirrors replaced by the
+/// `fallback`th of their `fallback_mirrors`, if given), and perform the initial update.
+async fn finish_system(
+    mut system: System,
+    sources: impl BufRead,
+    fallback: Option<usize>,
+    config: &MadisonConfig,
+) -> Result<System, anyhow::Error> {
     commands::add_builtin_keys(&mut system);
-    system.add_sources_entries(sources_list::read(BufReader::new(
-        File::open(&config.sources_list).unwrap(),
-    ))?);
+    let mut entries = sources_list::read(sources)?;
+    if let Some(fallback) = fallback {
+        rewrite_mirrors(
+            entries.iter_mut().map(|entry| &mut entry.url),
+            &config.fallback_mirrors,
+            fallback,
+        );
+    }
+    system.add_sources_entries(entries);
 
-    system.set_arches(&config.arches);
-    system.update().await?;
+    // Excluded architectures' indices aren't even downloaded
+    system.set_arches(&config.tracked_arches());
+    update_system(&system, config.initial_update_timeout_secs).await?;
     Ok(system)
 }
 
-fn build_madison_mapping(
-    system: &System,
-    key_func: &key_func::KeyFunc,
-    include_source_arch: bool,
-) -> Result<MadisonMapping, anyhow::Error> {
-    // Collect all the versions
-    let versions: Vec<Vec<_>> = system
-        .listings()?
-        .par_iter()
-        .map(|downloaded_list| -> Result<_, anyhow::Error> {
-            let key = key_func(downloaded_list);
-            let mut versions: HashMap<_, (String, HashSet<_>)> = HashMap::new();
-            for section in system.open_listing(downloaded_list)? {
-                let pkg = section?.as_pkg()?;
-                if let Some(bin) = pkg.as_bin() {
-                    let mut pkg_types = HashMap::new();
-                    if include_source_arch {
-                        pkg_types
-                            .entry(match bin.source.as_ref() {
-                                Some(source_pkg_name) => source_pkg_name.clone(),
-                                None => pkg.name.clone(),
-                            })
-                            .or_insert(HashSet::new())
-                            .insert("source".to_string());
-                    }
-                    pkg_types.entry(pkg.name).or_insert(HashSet::new()).insert(
-                        downloaded_list
-                            .listing
-                            .arch
-                            .as_ref()
-                            .unwrap_or(&"unknown!".to_string())
-                            .clone(),
-                    );
-                    for (pkg_name, pkg_types) in pkg_types.into_iter() {
-                        match versions.entry(pkg_name) {
-                            Entry::Occupied(mut o) => {
-                                let (current_version, types) = o.get_mut();
-                                types.extend(pkg_types);
-                                if deb_version::compare_versions(&pkg.version, current_version)
-                                    == Ordering::Greater
-                                {
-                                    *current_version = pkg.version.clone()
-                                }
-                            }
-                            Entry::Vacant(o) => {
-                                o.insert((pkg.version.clone(), pkg_types));
-                            }
-                        }
-                    }
-                }
+pub async fn init_system(config: &MadisonConfig) -> Result<System, anyhow::Error> {
+    init_system_via(config, None).await
+}
+
+/// Like `init_system`, but fetching sources list entries from the `fallback`th (from 0) of their
+/// mirrors' `fallback_mirrors`; see `rewrite_mirrors`.
+pub async fn init_system_with_fallback(
+    config: &MadisonConfig,
+    fallback: usize,
+) -> Result<System, anyhow::Error> {
+    init_system_via(config, Some(fallback)).await
+}
+
+/// How many fallbacks `init_system_with_fallback` can be asked for: the length of the longest of
+/// the configured `fallback_mirrors`.
+pub fn fallback_count(config: &MadisonConfig) -> usize {
+    config
+        .fallback_mirrors
+        .values()
+        .map(Vec::len)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Point each of `urls` at the `fallback`th of the mirrors `fallback_mirrors` gives for it,
+/// leaving those with no such fallback as they are.  URLs are compared ignoring any trailing `/`.
+fn rewrite_mirrors<'a>(
+    urls: impl IntoIterator<Item = &'a mut String>,
+    fallback_mirrors: &HashMap<String, Vec<String>>,
+    fallback: usize,
+) {
+    for url in urls {
+        let mirror = fallback_mirrors
+            .iter()
+            .find(|(primary, _)| primary.trim_end_matches('/') == url.trim_end_matches('/'))
+            .and_then(|(_, mirrors)| mirrors.get(fallback));
+        if let Some(mirror) = mirror {
+            *url = mirror.to_string();
+            // Otherwise the mirror's last path segment would be replaced, not appended to
+            if !url.ends_with('/') {
+                url.push('/');
             }
-            Ok(versions
-                .into_iter()
-                .map(|(package_name, (version, types))| (package_name, key.clone(), version, types))
-                .collect())
-        })
-        .collect::<Result<_, _>>()?;
+        }
+    }
+}
 
-    let mut merged_versions: MadisonMapping = HashMap::new();
-    for (package, codename, codename_version, types) in versions.into_iter().flatten() {
-        let pkg_merged_versions = merged_versions.entry(package).or_insert(HashMap::new());
-        let key = (codename, codename_version);
-        if let Some(current_value) = pkg_merged_versions.get_mut(&key) {
-            (*current_value).extend(types.to_owned());
+async fn init_system_via(
+    config: &MadisonConfig,
+    fallback: Option<usize>,
+) -> Result<System, anyhow::Error> {
+    // Setup the system
+    let mut system = new_system(config)?;
+    load_key_files(&config.extra_key_paths, |path| {
+        system.add_keys_from(Cursor::new(read_key_file(path)?))?;
+        Ok(())
+    })?;
+    let sources = read_sources_list(&config.sources_list)?;
+    finish_system(system, sources.as_bytes(), fallback, config).await
+}
+
+/// Load each of the key files at `paths` with `load`, warning about those which fail.  A key file
+/// that's momentarily unreadable shouldn't prevent startup, so this only fails if none load.
+fn load_key_files(
+    paths: &[String],
+    mut load: impl FnMut(&str) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    let mut loaded_keys = 0;
+    for path in paths {
+        match load(path) {
+            Ok(()) => loaded_keys += 1,
+            Err(e) => warn!("Failed to load keys from {}: {}", path, e),
+        }
+    }
+    if !paths.is_empty() && loaded_keys == 0 {
+        anyhow::bail!("none of the configured extra_key_paths could be loaded");
+    }
+    Ok(())
+}
+
+/// The content of the sources list at `path`, in one-line form: either a file, or a directory
+/// (like `/etc/apt/sources.list.d`) whose `.list` and `.sources` files are concatenated in name
+/// order, as apt does.  `.sources` files are deb822-style, and converted.
+fn read_sources_list(path: &str) -> Result<String, anyhow::Error> {
+    let path = std::path::Path::new(path);
+    let is_sources = |path: &std::path::Path| path.extension().is_some_and(|ext| ext == "sources");
+    let read = |path: &std::path::Path| -> Result<String, anyhow::Error> {
+        let content = std::fs::read_to_string(path)?;
+        if is_sources(path) {
+            deb822::to_one_line(&content)
+                .map_err(|e| anyhow::anyhow!("failed to parse {}: {}", path.display(), e))
         } else {
-            pkg_merged_versions.insert(key, types.to_owned());
+            Ok(content)
+        }
+    };
+    if !path.is_dir() {
+        return read(path);
+    }
+    let mut paths = vec![];
+    for dir_entry in std::fs::read_dir(path)? {
+        let entry_path = dir_entry?.path();
+        if entry_path.is_file()
+            && (is_sources(&entry_path) || entry_path.extension().is_some_and(|ext| ext == "list"))
+        {
+            paths.push(entry_path);
         }
     }
-    Ok(merged_versions)
+    paths.sort();
+    let mut sources = String::new();
+    for entry_path in paths {
+        sources.push_str(&read(&entry_path)?);
+        // A file without a trailing newline mustn't run into the next file's first entry
+        sources.push('\n');
+    }
+    Ok(sources)
 }
 
-pub fn generate_madison_structure(
-    madison_mapping: &MadisonMapping,
-    packages: &Vec<String>,
-    suite: Option<String>,
-) -> MadisonStructure {
-    packages
-        .par_iter()
-        .filter_map(|package| {
-            madison_mapping
-                .get(package)
-                .map(|entries| (package.clone(), entries))
+/// Like `init_system`, but with the sources list and keys given directly rather than read from
+/// the files named by `config`'s `sources_list` and `extra_key_paths` (which are ignored).
+pub async fn init_system_from(
+    config: &MadisonConfig,
+    sources_list: &str,
+    keys: &[&[u8]],
+) -> Result<System, anyhow::Error> {
+    let mut system = new_system(config)?;
+    for key in keys {
+        system.add_keys_from(Cursor::new(*key))?;
+    }
+    finish_system(system, sources_list.as_bytes(), None, config).await
+}
+
+/// Build a mapping from in-memory sources list content and keys; see `init_system_from`.
+pub async fn build_madison_mapping_from(
+    config: &MadisonConfig,
+    sources_list: &str,
+    keys: &[&[u8]],
+    key_func: &key_func::KeyFunc,
+) -> Result<MadisonMapping, anyhow::Error> {
+    let system = init_system_from(config, sources_list, keys).await?;
+    build_madison_mapping(&system, key_func, config)
+}
+
+/// Update `system`, giving up after `timeout_secs` (if given).  Returns whether anything changed.
+pub async fn update_system(
+    system: &System,
+    timeout_secs: Option<u64>,
+) -> Result<bool, anyhow::Error> {
+    Ok(match timeout_secs {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), system.update())
+            .await
+            .map_err(|_| anyhow::anyhow!("update timed out after {}s", secs))??,
+        None => system.update().await?,
+    })
+}
+
+/// The value of a field fapt doesn't parse itself, with continuation lines joined.
+fn raw_field(pkg: &Package, field: &str) -> Option<String> {
+    pkg.unparsed.get(field).map(|lines| lines.join(" "))
+}
+
+/// The package names in a relationship field like `foo (= 1.0), bar (= 2.0)`.
+fn relationship_names(field: &str) -> HashSet<String> {
+    field
+        .split(',')
+        .filter_map(|relationship| {
+            relationship
+                .split(|c: char| c.is_whitespace() || c == '(')
+                .find(|name| !name.is_empty())
+                .map(str::to_string)
         })
-        .map(|(package, entries)| {
-            let mut merged_vec = entries
-                .into_iter()
-                .filter(|((codename, _), _)| {
-                    suite
-                        .as_ref()
-                        .map(|suite| codename == suite)
-                        .unwrap_or(true)
-                })
-                .collect::<Vec<_>>();
-            merged_vec.sort_by(|((codename1, v1), _), ((codename2, v2), _)| {
-                match deb_version::compare_versions(v1, v2) {
-                    Ordering::Equal => codename1.cmp(codename2),
-                    other => other,
-                }
-            });
-            (package, merged_vec)
+        .collect()
+}
+
+fn priority_name(priority: &Priority) -> Option<&'static str> {
+    match priority {
+        Priority::Required => Some("required"),
+        Priority::Important => Some("important"),
+        Priority::Standard => Some("standard"),
+        Priority::Optional => Some("optional"),
+        Priority::Extra => Some("extra"),
+        _ => None,
+    }
+}
+
+/// The suites (as keyed by `key_func`) which come from more than one mirror, with those mirrors.
+pub fn conflicting_suites(
+    listings: &[DownloadedList],
+    key_func: &key_func::KeyFunc,
+) -> HashMap<String, Vec<String>> {
+    suites_with_several_mirrors(listings.iter().map(|downloaded_list| {
+        (
+            key_func(downloaded_list),
+            downloaded_list.release.req.mirror.to_string(),
+        )
+    }))
+}
+
+/// The suites among `sources` of `(suite, mirror)` which have more than one mirror, with those
+/// mirrors (sorted).
+fn suites_with_several_mirrors(
+    sources: impl IntoIterator<Item = (String, String)>,
+) -> HashMap<String, Vec<String>> {
+    let mut suite_mirrors: HashMap<String, HashSet<String>> = HashMap::new();
+    for (suite, mirror) in sources {
+        suite_mirrors.entry(suite).or_default().insert(mirror);
+    }
+    suite_mirrors
+        .into_iter()
+        .filter(|(_, mirrors)| mirrors.len() > 1)
+        .map(|(suite, mirrors)| {
+            let mut mirrors: Vec<_> = mirrors.into_iter().collect();
+            mirrors.sort();
+            (suite, mirrors)
         })
-        .map(|(package, merged_vec)| {
-            let lines: Vec<_> = merged_vec
-                .into_iter()
-                .map(|((codename, codename_version), types)| {
-                    // Start with "source", append sorted architectures, join with ", "
-                    let mut types = types.clone();
-                    let mut type_parts: Vec<_> = types.take("source").into_iter().collect();
-                    let mut arch_parts: Vec<_> = types.into_iter().collect();
-                    arch_parts.sort();
-                    type_parts.extend(arch_parts);
-                    MadisonOutputRecord::new(
-                        package.to_owned(),
-                        codename_version.to_string(),
-                        codename.to_string(),
-                        type_parts.join(", "),
-                    )
-                })
-                .collect();
-            (package, lines)
+        .collect()
+}
+
+/// Whether `list`'s Release file is past its `Valid-Until` date (if it has one) at `now`.
+fn is_expired(list: &DownloadedList, now: SystemTime) -> bool {
+    is_past(list.release.valid_until.map(SystemTime::from), now)
+}
+
+/// Whether `valid_until` (if there is one) is before `now`.
+fn is_past(valid_until: Option<SystemTime>, now: SystemTime) -> bool {
+    valid_until
+        .map(|valid_until| valid_until < now)
+        .unwrap_or(false)
+}
+
+/// The suites, as keyed by `key_func`, with a Release file past its `Valid-Until` date.
+pub fn expired_suites(
+    system: &System,
+    key_func: &key_func::KeyFunc,
+    config: &MadisonConfig,
+) -> Result<Vec<String>, anyhow::Error> {
+    let now = SystemTime::now();
+    let mut suites: Vec<_> = system
+        .listings()?
+        .iter()
+        .filter(|downloaded_list| is_expired(downloaded_list, now))
+        .map(|downloaded_list| listing_key(downloaded_list, key_func, config))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    suites.sort();
+    Ok(suites)
+}
+
+/// A fingerprint of the Release files behind each key (as produced by `key_func`), which changes
+/// whenever any index they cover does.
+///
+/// Release files record the size and hash of each index, so this detects changes regardless of
+/// what `System::update` reports, and (unlike their `Date` or `Valid-Until`) these don't change
+/// when an archive merely re-signs an unchanged Release.
+pub fn release_fingerprints(
+    system: &System,
+    key_func: &key_func::KeyFunc,
+    config: &MadisonConfig,
+) -> Result<HashMap<String, u64>, anyhow::Error> {
+    Ok(key_fingerprints(system.listings()?.iter().map(
+        |downloaded_list| {
+            (
+                listing_key(downloaded_list, key_func, config),
+                release_contents_hash(downloaded_list),
+            )
+        },
+    )))
+}
+
+/// A hash of where `list`'s Release file came from and the indices it lists.
+fn release_contents_hash(list: &DownloadedList) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    list.release.req.mirror.as_str().hash(&mut hasher);
+    list.release.req.codename.hash(&mut hasher);
+    for content in &list.release.contents {
+        content.name.hash(&mut hasher);
+        content.len.hash(&mut hasher);
+        content.hashes.sha256.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Combine the Release hashes of each key's listings into one fingerprint per key, regardless of
+/// their order (and of how many listings share a Release).
+fn key_fingerprints(
+    release_hashes: impl IntoIterator<Item = (String, u64)>,
+) -> HashMap<String, u64> {
+    let mut by_key: HashMap<String, Vec<u64>> = HashMap::new();
+    for (key, hash) in release_hashes {
+        by_key.entry(key).or_default().push(hash);
+    }
+    by_key
+        .into_iter()
+        .map(|(key, mut hashes)| {
+            hashes.sort_unstable();
+            hashes.dedup();
+            let mut hasher = DefaultHasher::new();
+            hashes.hash(&mut hasher);
+            (key, hasher.finish())
         })
         .collect()
 }
 
-pub fn do_madison(package_lines: &mut MadisonStructure, packages: Vec<String>) -> String {
-    let mut output_builder = Builder::default();
-    for package in packages {
-        let merged_vec = if let Some(merged_vec) = package_lines.remove(&package) {
-            merged_vec
-        } else {
-            continue;
-        };
-        for line in merged_vec {
-            output_builder.push_record(line);
-        }
+/// The keys whose fingerprint differs between `old` and `new`, including those only in one.
+pub fn changed_keys(old: &HashMap<String, u64>, new: &HashMap<String, u64>) -> HashSet<String> {
+    old.keys()
+        .chain(new.keys())
+        .filter(|key| old.get(*key) != new.get(*key))
+        .cloned()
+        .collect()
+}
+
+/// Replace the rows of `madison_mapping` under `keys` with those in `partial` (which should be built
+/// from only those keys, by `build_madison_mapping_of`).
+pub fn replace_keys(
+    madison_mapping: &mut MadisonMapping,
+    partial: MadisonMapping,
+    keys: &HashSet<String>,
+) {
+    madison_mapping.retain(|_, entries| {
+        entries.retain(|(key, _), _| !keys.contains(key));
+        !entries.is_empty()
+    });
+    for (package, entries) in partial {
+        madison_mapping.entry(package).or_default().extend(entries);
     }
-    format!(
-        "{}\n",
-        output_builder
-            .build()
-            .with(Style::empty().vertical('|'))
-            .to_string()
-            .lines()
-            .map(|line| line.trim())
-            .collect::<Vec<&str>>()
-            .join("\n")
-    )
 }
 
-pub mod key_func {
-    use fapt::system::DownloadedList;
+fn build_madison_mapping(
+    system: &System,
+    key_func: &key_func::KeyFunc,
+    config: &MadisonConfig,
+) -> Result<MadisonMapping, anyhow::Error> {
+    build_madison_mapping_of(system, key_func, config, None, None)
+}
 
-    pub type KeyFunc = dyn Fn(&DownloadedList) -> String + Sync + 'static;
+/// The rows parsed from one listing: (package, key, version, entry).
+type ListingRows = Vec<(String, String, String, MadisonEntry)>;
 
-    pub fn codename(list: &DownloadedList) -> String {
-        list.release.req.codename.to_owned()
+/// The rows parsed from each listing by previous builds, so that rebuilds only parse listings
+/// which have changed since.  A listing is taken to be unchanged while the size and hash its
+/// Release file gives for its index are, however often the Release itself is republished.
+///
+/// This keeps a second copy of every listing's rows, roughly doubling the memory a mapping
+/// needs.  The rows depend on the key function and configuration, so each cache should only be
+/// used for builds with the same ones (and be replaced when the configuration is reloaded).
+#[derive(Default)]
+pub struct ListingCache {
+    /// Keyed by `listing_fingerprint`, with the listing's key
+    rows: Mutex<HashMap<u64, (String, ListingRows)>>,
+}
+
+impl ListingCache {
+    pub fn new() -> Self {
+        ListingCache::default()
     }
 
-    pub fn component(list: &DownloadedList) -> String {
-        list.listing.component.to_owned()
+    /// As `build_madison_mapping`, from only the lists whose key is in `keys` (or from every list
+    /// if `None`), reusing the rows of unchanged listings.
+    pub fn build(
+        &self,
+        system: &System,
+        key_func: &key_func::KeyFunc,
+        config: &MadisonConfig,
+        keys: Option<&HashSet<String>>,
+    ) -> Result<MadisonMapping, anyhow::Error> {
+        build_madison_mapping_of(system, key_func, config, keys, Some(self))
     }
 }
 
-pub mod madison_cli {
-    use figment::providers::{Format, Toml};
-    use figment::Figment;
-    use serde::Deserialize;
+/// Identifies one listing by the content its Release file records for it, so that it changes
+/// whenever the listing does.
+fn listing_fingerprint(list: &DownloadedList, key: &str, has_sources: bool) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    list.release.req.mirror.as_str().hash(&mut hasher);
+    list.release.req.codename.hash(&mut hasher);
+    list.listing.component.hash(&mut hasher);
+    list.listing.arch.hash(&mut hasher);
+    key.hash(&mut hasher);
+    has_sources.hash(&mut hasher);
+    let index_path = listing_index_path(&list.listing.component, list.listing.arch.as_deref());
+    index_hash(
+        list.release
+            .contents
+            .iter()
+            .map(|content| (content.name.as_str(), content.len, &content.hashes.sha256)),
+        &index_path,
+    )
+    .hash(&mut hasher);
+    hasher.finish()
+}
 
-    use crate::{
-        build_madison_mapping, do_madison, generate_madison_structure, init_system, key_func,
-        MadisonConfig,
+/// Where a listing's index is, relative to its Release file, before any compression suffix: e.g.
+/// `main/binary-amd64/Packages`, or just `Packages` in a flat repository (which has no
+/// components).
+fn listing_index_path(component: &str, arch: Option<&str>) -> String {
+    let index = match arch {
+        Some(arch) if !component.is_empty() => format!("binary-{}/Packages", arch),
+        Some(_) => "Packages".to_string(),
+        None if !component.is_empty() => "source/Sources".to_string(),
+        None => "Sources".to_string(),
     };
-
-    #[derive(Deserialize)]
-    struct CliConfig {
-        global: MadisonConfig,
+    if component.is_empty() {
+        index
+    } else {
+        format!("{}/{}", component, index)
     }
+}
 
-    pub async fn cli(key_func: &key_func::KeyFunc) {
-        let package = std::env::args().nth(1).expect("no package name given");
-        let config: CliConfig = Figment::new()
-            .merge(Toml::file("Rocket.toml"))
-            .extract()
-            .expect("reading Rocket.toml configuration");
+/// A hash of the Release `contents` entries (name, size and SHA256) for the index at
+/// `index_path`, in each of its compressions.
+fn index_hash<'a>(
+    contents: impl IntoIterator<Item = (&'a str, u64, &'a [u8; 32])>,
+    index_path: &str,
+) -> u64 {
+    let mut entries: Vec<_> = contents
+        .into_iter()
+        .filter(|(name, _, _)| {
+            name.strip_prefix(index_path)
+                .map(|suffix| suffix.is_empty() || suffix.starts_with('.'))
+                .unwrap_or(false)
+        })
+        .collect();
+    entries.sort();
+    let mut hasher = DefaultHasher::new();
+    entries.hash(&mut hasher);
+    hasher.finish()
+}
 
-        let system = init_system(&config.global).await.expect("fapt System init");
-        let madison_mapping =
-            build_madison_mapping(&system, key_func, config.global.include_source_arch)
-                .expect("build madison mapping");
-        let packages = vec![package];
-        let mut madison = generate_madison_structure(&madison_mapping, &packages, None);
-        print!("{}", do_madison(&mut madison, packages));
+/// The fields of a binary package's stanza which the mapping records.
+#[derive(Clone, Debug, Default)]
+struct BinaryStanza {
+    name: String,
+    version: String,
+    /// The source package it was built from (if not named the same)
+    source: Option<String>,
+    /// Whether it is `Architecture: all`
+    arch_all: bool,
+    priority: Option<String>,
+    essential: bool,
+    built_using: HashSet<String>,
+    multi_arch: Option<String>,
+    size: Option<u64>,
+    installed_size: Option<u64>,
+    tags: HashSet<String>,
+}
+
+/// The fields of a source package's stanza (from a Sources index) which the mapping records.
+#[derive(Clone, Debug, Default)]
+struct SourceStanza {
+    name: String,
+    version: String,
+    binaries: HashSet<String>,
+}
+
+/// A stanza of a listing, reduced to what the mapping records.
+enum Stanza {
+    Binary(BinaryStanza),
+    Source(SourceStanza),
+}
+
+impl Stanza {
+    /// `pkg`'s stanza, or `None` if it is neither a binary nor a source package.
+    fn from_package(pkg: &Package) -> Option<Stanza> {
+        if let Some(bin) = pkg.as_bin() {
+            return Some(Stanza::Binary(BinaryStanza {
+                name: pkg.name.clone(),
+                version: pkg.version.clone(),
+                source: bin.source.clone(),
+                arch_all: pkg.arches.iter().any(|arch| arch == ARCH_ALL),
+                priority: priority_name(&pkg.priority).map(str::to_string),
+                essential: bin.essential,
+                built_using: raw_field(pkg, "Built-Using")
+                    .map(|built_using| relationship_names(&built_using))
+                    .unwrap_or_default(),
+                multi_arch: raw_field(pkg, "Multi-Arch"),
+                size: raw_field(pkg, "Size").and_then(|size| size.parse().ok()),
+                installed_size: raw_field(pkg, "Installed-Size")
+                    .and_then(|installed_size| installed_size.parse().ok()),
+                tags: raw_field(pkg, "Tag")
+                    .map(|tags| {
+                        tags.split(',')
+                            .map(|tag| tag.trim().to_string())
+                            .filter(|tag| !tag.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            }));
+        }
+        let src = pkg.as_src()?;
+        Some(Stanza::Source(SourceStanza {
+            name: pkg.name.clone(),
+            version: pkg.version.clone(),
+            binaries: src
+                .binaries
+                .iter()
+                .map(|binary| binary.name.clone())
+                .collect(),
+        }))
+    }
+}
+
+/// Where a listing's stanzas are recorded: under `key`, as architecture `arch` (`None` for a
+/// Sources index).
+#[derive(Clone, Copy)]
+struct ListingContext<'a> {
+    key: &'a str,
+    arch: Option<&'a str>,
+    /// Whether `key` has a Sources index, which source versions are then taken from rather than
+    /// inferred from binaries
+    has_sources: bool,
+}
+
+/// The entries a binary's stanza contributes to, by package name.
+fn binary_entries(
+    binary: BinaryStanza,
+    listing: &ListingContext,
+    config: &MadisonConfig,
+) -> HashMap<String, MadisonEntry> {
+    let mut pkg_entries: HashMap<_, MadisonEntry> = HashMap::new();
+    let source_name = binary.source.unwrap_or_else(|| binary.name.clone());
+    // Without a Sources index, a source is taken to be at its binaries' version, which is wrong
+    // for binNMUs (e.g. binary 1.0+b1 of source 1.0)
+    if config.include_source_arch && !listing.has_sources {
+        let source_entry = pkg_entries.entry(source_name.clone()).or_default();
+        source_entry.types.insert(PackageType::Source);
+        source_entry.binaries.insert(binary.name.clone());
+    }
+    // When binaries aren't indexed, their architectures are recorded against their source instead
+    let arch_entry = pkg_entries
+        .entry(if config.index_binaries {
+            binary.name
+        } else {
+            source_name
+        })
+        .or_default();
+    // An `Architecture: all` binary is the same in every architecture's listing, so it gets a
+    // single `all` rather than each listing's architecture.  A listing without an architecture
+    // contributes no architecture to the row, so (unless its source is recorded) the binary isn't
+    // shown
+    if binary.arch_all {
+        arch_entry
+            .types
+            .insert(PackageType::Arch(ARCH_ALL.to_string()));
+    } else if let Some(arch) = listing.arch {
+        arch_entry.types.insert(PackageType::Arch(arch.to_string()));
+    }
+    if config.index_binaries {
+        arch_entry.priority = binary.priority;
+        arch_entry.essential = binary.essential;
+        arch_entry.built_using = binary.built_using;
+        arch_entry.multi_arch = binary.multi_arch;
+        arch_entry.size = binary.size;
+        arch_entry.installed_size = binary.installed_size;
+        arch_entry.tags = binary.tags;
+    }
+    pkg_entries
+}
+
+/// The rows recorded from one listing's `stanzas`, each package at the newest version the listing
+/// has of it.
+fn listing_rows(
+    stanzas: impl IntoIterator<Item = Result<Stanza, anyhow::Error>>,
+    listing: &ListingContext,
+    config: &MadisonConfig,
+    comparator: &dyn VersionComparator,
+) -> Result<ListingRows, anyhow::Error> {
+    let mut listing = *listing;
+    // Binaries recorded as architecture `source` would be indistinguishable from the source
+    // package itself
+    if listing.arch == Some("source") {
+        warn!(
+            "A listing of {} claims architecture \"source\"; recording its binaries without one",
+            listing.key
+        );
+        listing.arch = None;
+    }
+    let mut versions: HashMap<_, (String, MadisonEntry)> = HashMap::new();
+    for stanza in stanzas {
+        let (version, pkg_entries) = match stanza? {
+            Stanza::Binary(binary) => (
+                binary.version.clone(),
+                binary_entries(binary, &listing, config),
+            ),
+            Stanza::Source(source) => {
+                if !config.include_source_arch {
+                    continue;
+                }
+                let source_entry = MadisonEntry {
+                    types: HashSet::from([PackageType::Source]),
+                    binaries: source.binaries,
+                    ..Default::default()
+                };
+                (source.version, HashMap::from([(source.name, source_entry)]))
+            }
+        };
+        for (pkg_name, pkg_entry) in pkg_entries {
+            match versions.entry(pkg_name) {
+                Entry::Occupied(mut o) => {
+                    let (current_version, entry) = o.get_mut();
+                    entry.merge(pkg_entry);
+                    if comparator.compare(&version, current_version) == Ordering::Greater {
+                        *current_version = version.clone()
+                    }
+                }
+                Entry::Vacant(o) => {
+                    o.insert((version.clone(), pkg_entry));
+                }
+            }
+        }
+    }
+    Ok(versions
+        .into_iter()
+        .map(|(package_name, (version, entry))| {
+            (package_name, listing.key.to_string(), version, entry)
+        })
+        .collect())
+}
+
+/// What a listing is of, whichever mirror it came from: its key, component and architecture.
+type ListingSlot = (String, String, Option<String>);
+
+/// Merge the rows of several listings into a mapping.  Where more than one mirror provides the
+/// same listing, each package is only recorded at the newest version any of them has.
+fn merge_rows(
+    listings: impl IntoIterator<Item = (ListingSlot, ListingRows)>,
+    comparator: &dyn VersionComparator,
+) -> MadisonMapping {
+    let mut slots: HashMap<ListingSlot, HashMap<String, (String, String, MadisonEntry)>> =
+        HashMap::new();
+    for (slot, rows) in listings {
+        let newest = slots.entry(slot).or_default();
+        for (package, key, version, entry) in rows {
+            match newest.entry(package) {
+                Entry::Occupied(mut o) => {
+                    let current = o.get_mut();
+                    match comparator.compare(&version, &current.1) {
+                        Ordering::Greater => *current = (key, version, entry),
+                        Ordering::Equal => current.2.merge(entry),
+                        Ordering::Less => {}
+                    }
+                }
+                Entry::Vacant(o) => {
+                    o.insert((key, version, entry));
+                }
+            }
+        }
+    }
+    let mut merged_versions: MadisonMapping = HashMap::new();
+    for (package, (codename, codename_version, entry)) in slots.into_values().flatten() {
+        let pkg_merged_versions = merged_versions.entry(package).or_insert(HashMap::new());
+        let key = (codename, codename_version);
+        if let Some(current_value) = pkg_merged_versions.get_mut(&key) {
+            current_value.merge(entry);
+        } else {
+            pkg_merged_versions.insert(key, entry);
+        }
+    }
+    merged_versions
+}
+
+fn listing_slot(list: &DownloadedList, key: &str) -> ListingSlot {
+    (
+        key.to_string(),
+        list.listing.component.clone(),
+        list.listing.arch.clone(),
+    )
+}
+
+/// Build the mapping from only the lists whose key is in `keys`, or from every list if `None`,
+/// reusing (and updating) the rows in `cache` if given.
+fn build_madison_mapping_of(
+    system: &System,
+    key_func: &key_func::KeyFunc,
+    config: &MadisonConfig,
+    keys: Option<&HashSet<String>>,
+    cache: Option<&ListingCache>,
+) -> Result<MadisonMapping, anyhow::Error> {
+    let comparator = config.version_comparator()?;
+    let listings = system.listings()?;
+    // Rows from each of these are merged, which may be surprising if the mirrors disagree
+    if config.warn_conflicting_mirrors {
+        for (suite, mirrors) in conflicting_suites(&listings, key_func) {
+            warn!(
+                "{} is provided by multiple mirrors, whose packages will be merged: {}",
+                suite,
+                mirrors.join(", ")
+            );
+        }
+    }
+
+    let now = SystemTime::now();
+    for downloaded_list in listings.iter().filter(|list| is_expired(list, now)) {
+        warn!(
+            "Release for {} from {} is past its Valid-Until date{}",
+            downloaded_list.release.req.codename,
+            downloaded_list.release.req.mirror,
+            if config.refuse_expired_suites {
+                "; skipping it"
+            } else {
+                ""
+            }
+        );
+    }
+
+    let included: Vec<_> = listings
+        .iter()
+        .filter(|downloaded_list| {
+            !(config.refuse_expired_suites && is_expired(downloaded_list, now))
+        })
+        .filter(|downloaded_list| {
+            keys.map(|keys| keys.contains(&listing_key(downloaded_list, key_func, config)))
+                .unwrap_or(true)
+        })
+        .filter(|downloaded_list| {
+            downloaded_list
+                .listing
+                .arch
+                .as_ref()
+                .map(|arch| !config.exclude_arches.contains(arch))
+                .unwrap_or(true)
+        })
+        .filter(|downloaded_list| config.includes_component(&downloaded_list.listing.component))
+        .collect();
+    // Keys with a Sources index (the listings without an architecture), whose source versions
+    // are taken from it rather than inferred from their binaries
+    let keys_with_sources: HashSet<String> = included
+        .iter()
+        .filter(|downloaded_list| downloaded_list.listing.arch.is_none())
+        .map(|downloaded_list| listing_key(downloaded_list, key_func, config))
+        .collect();
+
+    // Collect all the versions
+    let versions: Vec<(u64, ListingSlot, ListingRows)> = included
+        .par_iter()
+        .map(|downloaded_list| -> Result<_, anyhow::Error> {
+            let key = listing_key(downloaded_list, key_func, config);
+            let fingerprint =
+                listing_fingerprint(downloaded_list, &key, keys_with_sources.contains(&key));
+            if let Some(cache) = cache {
+                let cached = cache
+                    .rows
+                    .lock()
+                    .expect("cache lock")
+                    .get(&fingerprint)
+                    .cloned();
+                if let Some((_, rows)) = cached {
+                    return Ok((fingerprint, listing_slot(downloaded_list, &key), rows));
+                }
+            }
+            let listing = ListingContext {
+                key: &key,
+                arch: downloaded_list.listing.arch.as_deref(),
+                has_sources: keys_with_sources.contains(&key),
+            };
+            let stanzas = system
+                .open_listing(downloaded_list)?
+                .into_iter()
+                .map(|section| -> Result<_, anyhow::Error> {
+                    Ok(Stanza::from_package(&section?.as_pkg()?))
+                })
+                .filter_map(Result::transpose);
+            let rows = listing_rows(stanzas, &listing, config, comparator)?;
+            let slot = listing_slot(downloaded_list, &key);
+            if let Some(cache) = cache {
+                cache
+                    .rows
+                    .lock()
+                    .expect("cache lock")
+                    .insert(fingerprint, (key, rows.clone()));
+            }
+            Ok((fingerprint, slot, rows))
+        })
+        .collect::<Result<_, _>>()?;
+
+    if let Some(cache) = cache {
+        // Forget listings which have changed or gone, among those this build covered
+        let used: HashSet<_> = versions
+            .iter()
+            .map(|(fingerprint, _, _)| *fingerprint)
+            .collect();
+        cache
+            .rows
+            .lock()
+            .expect("cache lock")
+            .retain(|fingerprint, (key, _)| {
+                used.contains(fingerprint) || keys.map(|keys| !keys.contains(key)).unwrap_or(false)
+            });
+    }
+
+    Ok(merge_rows(
+        versions.into_iter().map(|(_, slot, rows)| (slot, rows)),
+        comparator,
+    ))
+}
+
+/// The types for a row of the source package `entry` in `codename`: `source`, and the
+/// architectures of the binaries built from it there.  Architectures of any same-named binary not
+/// built from this source are left out.
+fn source_types(
+    madison_mapping: &MadisonMapping,
+    codename: &str,
+    entry: &MadisonEntry,
+) -> HashSet<PackageType> {
+    let mut types = HashSet::from([PackageType::Source]);
+    for binary in &entry.binaries {
+        if let Some(binary_entries) = madison_mapping.get(binary) {
+            types.extend(
+                binary_entries
+                    .iter()
+                    .filter(|((binary_codename, _), _)| binary_codename == codename)
+                    .flat_map(|(_, binary_entry)| &binary_entry.types)
+                    .filter(|package_type| **package_type != PackageType::Source)
+                    .cloned(),
+            );
+        }
+    }
+    types
+}
+
+/// When only some architectures are shown, count an `all` binary as present on each of those
+/// which is a concrete architecture (since it installs on any of them), unless `all` itself was
+/// asked for.
+fn expand_arch_all(types: &mut HashSet<PackageType>, arches: &HashSet<String>) {
+    let all = PackageType::Arch(ARCH_ALL.to_string());
+    if arches.contains(ARCH_ALL) || !types.remove(&all) {
+        return;
+    }
+    types.extend(
+        arches
+            .iter()
+            .filter(|arch| *arch != "source")
+            .map(|arch| PackageType::Arch(arch.clone())),
+    );
+}
+
+pub fn generate_madison_structure(
+    madison_mapping: &MadisonMapping,
+    packages: &[String],
+    filter: &MadisonFilter,
+    comparator: &dyn VersionComparator,
+) -> MadisonStructure {
+    let mut package_lines: MadisonStructure = packages
+        .par_iter()
+        .filter_map(|package| {
+            madison_mapping
+                .get(package)
+                .map(|entries| (package.clone(), entries))
+        })
+        .map(|(package, entries)| {
+            let mut merged_vec = entries
+                .into_iter()
+                .filter(|((codename, version), entry)| {
+                    filter.matches(codename, version, entry, comparator)
+                })
+                .collect::<Vec<_>>();
+            // Order by version, then codename; versions which compare equal but are spelled
+            // differently (e.g. "1.0" and "0:1.0") fall back to their text so the order is total
+            merged_vec.sort_by(|((codename1, v1), _), ((codename2, v2), _)| {
+                comparator
+                    .compare(v1, v2)
+                    .then_with(|| codename1.cmp(codename2))
+                    .then_with(|| v1.cmp(v2))
+            });
+            (package, merged_vec)
+        })
+        .map(|(package, merged_vec)| {
+            let lines: Vec<_> = merged_vec
+                .into_iter()
+                .filter_map(|((codename, codename_version), entry)| {
+                    let mut row_types = if filter.source_only {
+                        source_types(madison_mapping, codename, entry)
+                    } else {
+                        entry.types.clone()
+                    };
+                    if let Some(arches) = &filter.arches {
+                        expand_arch_all(&mut row_types, arches);
+                    }
+                    // Start with "source", append sorted architectures, join with ", "
+                    let mut types: Vec<_> = row_types
+                        .iter()
+                        .filter(|package_type| {
+                            filter
+                                .arches
+                                .as_ref()
+                                .map(|arches| arches.contains(&package_type.to_string()))
+                                .unwrap_or(true)
+                        })
+                        .collect();
+                    if types.is_empty() {
+                        return None;
+                    }
+                    types.sort();
+                    let type_parts: Vec<_> = types.into_iter().map(ToString::to_string).collect();
+                    let mut record = MadisonOutputRecord::new(
+                        package.to_owned(),
+                        codename_version.to_string(),
+                        codename.to_string(),
+                        type_parts.join(", "),
+                    );
+                    if !entry.binaries.is_empty() {
+                        record.binary_count = Some(entry.binaries.len());
+                    }
+                    record.multi_arch = entry.multi_arch.clone();
+                    record.size = entry.size;
+                    record.installed_size = entry.installed_size;
+                    Some(record)
+                })
+                .collect();
+            (package, lines)
+        })
+        .collect();
+    for package in packages {
+        if filter.watched_packages.contains(package)
+            && package_lines
+                .get(package)
+                .map(Vec::is_empty)
+                .unwrap_or(true)
+        {
+            package_lines.insert(
+                package.clone(),
+                vec![MadisonOutputRecord::new(
+                    package.clone(),
+                    "not found".to_string(),
+                    String::new(),
+                    String::new(),
+                )],
+            );
+        }
+    }
+    package_lines
+}
+
+/// Bounds on regex queries, so that one can't take an unreasonable amount of time or produce an
+/// unreasonable amount of output.  `None` means unlimited.
+#[derive(Clone, Copy, Default)]
+pub struct RegexLimits {
+    pub max_length: Option<usize>,
+    pub max_matches: Option<usize>,
+}
+
+/// The packages in `madison_mapping` whose names match `pattern`, in name order.  Errors (with a
+/// message suitable for users) if `pattern` is invalid or exceeds `limits`.
+pub fn matching_packages(
+    madison_mapping: &MadisonMapping,
+    pattern: &str,
+    limits: RegexLimits,
+) -> Result<Vec<String>, anyhow::Error> {
+    if let Some(max_length) = limits.max_length {
+        if pattern.len() > max_length {
+            anyhow::bail!(
+                "regex is {} bytes long; the limit is {}",
+                pattern.len(),
+                max_length
+            );
+        }
+    }
+    let regex = RegexBuilder::new(pattern)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()?;
+    let mut packages: Vec<_> = madison_mapping
+        .keys()
+        .filter(|package| regex.is_match(package))
+        .cloned()
+        .collect();
+    if let Some(max_matches) = limits.max_matches {
+        if packages.len() > max_matches {
+            anyhow::bail!(
+                "regex {:?} matches {} packages, more than the limit of {}; please narrow it",
+                pattern,
+                packages.len(),
+                max_matches
+            );
+        }
+    }
+    packages.sort();
+    Ok(packages)
+}
+
+/// The largest compiled regex accepted (by the regex crate's measure), which bounds the work any
+/// pattern can cause.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// The number of records in `structure`, and of distinct packages they're for.  `watched_packages`
+/// placeholders aren't matches, so aren't counted.
+pub fn count_records(structure: &MadisonStructure) -> (usize, usize) {
+    let mut records = 0;
+    let mut packages = 0;
+    for lines in structure.values() {
+        let found = lines
+            .iter()
+            .filter(|line| !line.codename.is_empty())
+            .count();
+        records += found;
+        if found > 0 {
+            packages += 1;
+        }
+    }
+    (records, packages)
+}
+
+/// A minimal apt `Packages` index of the binaries in `suite`, limited to `arch` if given, from the
+/// fields the mapping records.  There is no `Filename`, so it's for listing, not for installing.
+pub fn packages_index(
+    madison_mapping: &MadisonMapping,
+    suite: &str,
+    arch: Option<&str>,
+    comparator: &dyn VersionComparator,
+) -> String {
+    let mut stanzas = vec![];
+    for (package, entries) in madison_mapping {
+        for ((codename, version), entry) in entries {
+            if codename != suite {
+                continue;
+            }
+            for package_type in &entry.types {
+                let entry_arch = match package_type {
+                    PackageType::Arch(entry_arch) => entry_arch,
+                    PackageType::Source => continue,
+                };
+                // As in the archive, each architecture's index includes `all` binaries
+                if arch
+                    .map(|arch| arch != entry_arch && entry_arch != ARCH_ALL)
+                    .unwrap_or(false)
+                {
+                    continue;
+                }
+                stanzas.push((package, version, entry_arch, entry));
+            }
+        }
+    }
+    stanzas.sort_by(|(p1, v1, a1, _), (p2, v2, a2, _)| {
+        p1.cmp(p2)
+            .then_with(|| comparator.compare(v1, v2))
+            .then_with(|| a1.cmp(a2))
+    });
+    let mut output = String::new();
+    for (package, version, arch, entry) in stanzas {
+        output.push_str(&format!(
+            "Package: {}\nVersion: {}\nArchitecture: {}\n",
+            package, version, arch
+        ));
+        if let Some(priority) = &entry.priority {
+            output.push_str(&format!("Priority: {}\n", priority));
+        }
+        if entry.essential {
+            output.push_str("Essential: yes\n");
+        }
+        if let Some(multi_arch) = &entry.multi_arch {
+            output.push_str(&format!("Multi-Arch: {}\n", multi_arch));
+        }
+        if !entry.tags.is_empty() {
+            let mut tags: Vec<_> = entry.tags.iter().map(String::as_str).collect();
+            tags.sort();
+            output.push_str(&format!("Tag: {}\n", tags.join(", ")));
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// The rows of every package whose version in some suite differs from its (newest) version in
+/// `reference_suite`.  Packages absent from `reference_suite` are not included.
+pub fn diverging_from(
+    madison_mapping: &MadisonMapping,
+    reference_suite: &str,
+    comparator: &dyn VersionComparator,
+) -> MadisonMapping {
+    madison_mapping
+        .iter()
+        .filter_map(|(package, entries)| {
+            let reference_version = entries
+                .keys()
+                .filter(|(codename, _)| codename == reference_suite)
+                .map(|(_, version)| version)
+                .max_by(|v1, v2| comparator.compare(v1, v2))?;
+            let diverging: HashMap<_, _> = entries
+                .iter()
+                .filter(|((codename, version), _)| {
+                    codename != reference_suite
+                        && comparator.compare(version, reference_version) != Ordering::Equal
+                })
+                .map(|(key, entry)| (key.clone(), entry.clone()))
+                .collect();
+            if diverging.is_empty() {
+                None
+            } else {
+                Some((package.clone(), diverging))
+            }
+        })
+        .collect()
+}
+
+/// The smallest version of `package` strictly greater than `version`, with the (sorted) suites
+/// containing it.
+pub fn closest_newer_version(
+    madison_mapping: &MadisonMapping,
+    package: &str,
+    version: &str,
+    comparator: &dyn VersionComparator,
+) -> Option<(String, Vec<String>)> {
+    let entries = madison_mapping.get(package)?;
+    let newer = entries
+        .keys()
+        .map(|(_, candidate)| candidate)
+        .filter(|candidate| comparator.compare(candidate, version) == Ordering::Greater)
+        .min_by(|v1, v2| comparator.compare(v1, v2))?;
+    let mut suites: Vec<_> = entries
+        .keys()
+        .filter(|(_, candidate)| comparator.compare(candidate, newer) == Ordering::Equal)
+        .map(|(codename, _)| codename.clone())
+        .collect();
+    suites.sort();
+    suites.dedup();
+    Some((newer.clone(), suites))
+}
+
+/// The position of `codename` in `suite_order`; suites not listed sort after all listed ones.
+fn suite_rank(suite_order: &[String], codename: &str) -> usize {
+    suite_order
+        .iter()
+        .position(|suite| suite == codename)
+        .unwrap_or(suite_order.len())
+}
+
+/// A package whose version in a suite is older than in a suite that comes after it in
+/// `suite_order`, e.g. a `-security` version older than the `-updates` one.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct VersionInversion {
+    pub package: String,
+    pub suite: String,
+    pub version: String,
+    pub later_suite: String,
+    pub later_version: String,
+}
+
+impl std::fmt::Display for VersionInversion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} has {}, older than {} in {}",
+            self.package, self.suite, self.version, self.later_version, self.later_suite
+        )
+    }
+}
+
+/// Every version inversion between the suites in `suite_order` (which new versions should reach
+/// in order, so each suite should have at least the version of every suite after it).  Sorted by
+/// package.
+pub fn find_version_inversions(
+    madison_mapping: &MadisonMapping,
+    suite_order: &[String],
+    comparator: &dyn VersionComparator,
+) -> Vec<VersionInversion> {
+    let mut inversions = vec![];
+    for (package, entries) in madison_mapping {
+        let versions = newest_by_suite(entries, comparator);
+        let ordered: Vec<_> = suite_order
+            .iter()
+            .filter_map(|suite| {
+                versions
+                    .get(suite.as_str())
+                    .map(|version| (suite, *version))
+            })
+            .collect();
+        for (idx, (suite, version)) in ordered.iter().enumerate() {
+            for (later_suite, later_version) in &ordered[idx + 1..] {
+                if comparator.compare(version, later_version) == Ordering::Less {
+                    inversions.push(VersionInversion {
+                        package: package.clone(),
+                        suite: suite.to_string(),
+                        version: version.to_string(),
+                        later_suite: later_suite.to_string(),
+                        later_version: later_version.to_string(),
+                    });
+                }
+            }
+        }
+    }
+    inversions.sort_by(|i1, i2| i1.package.cmp(&i2.package));
+    inversions
+}
+
+/// Reduce each package to a single record: its newest version, in the first suite (by
+/// `suite_order`, then by name) containing that version.  That is, where the newest version
+/// landed first.
+pub fn reduce_to_origin_suite(
+    package_lines: &mut MadisonStructure,
+    suite_order: &[String],
+    comparator: &dyn VersionComparator,
+) {
+    for records in package_lines.values_mut() {
+        let origin = records
+            .iter()
+            .enumerate()
+            .max_by(|(_, r1), (_, r2)| {
+                comparator.compare(&r1.version, &r2.version).then_with(|| {
+                    // Earlier suites are "greater", so max_by picks them
+                    (suite_rank(suite_order, &r2.codename), &r2.codename)
+                        .cmp(&(suite_rank(suite_order, &r1.codename), &r1.codename))
+                })
+            })
+            .map(|(idx, _)| idx);
+        if let Some(idx) = origin {
+            let record = records.swap_remove(idx);
+            *records = vec![record];
+        }
+    }
+}
+
+/// Reduce each package to a single record of its newest version.  If several suites have that
+/// version, the record lists them all (comma-separated, e.g. `trixie, sid`), with the
+/// architectures of any of them.
+pub fn reduce_to_latest(package_lines: &mut MadisonStructure, comparator: &dyn VersionComparator) {
+    for records in package_lines.values_mut() {
+        let latest = match records
+            .iter()
+            .max_by(|r1, r2| comparator.compare(&r1.version, &r2.version))
+        {
+            Some(latest) => latest.version.clone(),
+            None => continue,
+        };
+        let mut newest = records
+            .drain(..)
+            .filter(|record| comparator.compare(&record.version, &latest) == Ordering::Equal);
+        let mut record = newest.next().expect("the newest version has a record");
+        let mut codenames = vec![record.codename.clone()];
+        let mut arches: Vec<_> = record
+            .architectures
+            .split(", ")
+            .map(str::to_string)
+            .collect();
+        for other in newest {
+            codenames.push(other.codename);
+            for arch in other.architectures.split(", ") {
+                if !arches.iter().any(|known| known == arch) {
+                    arches.push(arch.to_string());
+                }
+            }
+        }
+        record.codename = codenames.join(", ");
+        record.architectures = arches.join(", ");
+        *records = vec![record];
+    }
+}
+
+/// The differences between two mappings, as produced by `diff_mappings`.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MappingDiff {
+    /// Packages only present in the new mapping
+    pub added: Vec<String>,
+    /// Packages only present in the old mapping
+    pub removed: Vec<String>,
+    /// Suite-level version changes of packages present in both mappings
+    pub changed: Vec<VersionChange>,
+}
+
+/// A change in the (newest) version of a package in one suite.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct VersionChange {
+    pub package: String,
+    pub suite: String,
+    /// `None` if the package wasn't previously in this suite
+    pub old_version: Option<String>,
+    /// `None` if the package is no longer in this suite
+    pub new_version: Option<String>,
+}
+
+/// The newest version of a package in each suite it's in.
+fn newest_by_suite<'a>(
+    entries: &'a HashMap<(String, String), MadisonEntry>,
+    comparator: &dyn VersionComparator,
+) -> HashMap<&'a str, &'a str> {
+    let mut newest: HashMap<&str, &str> = HashMap::new();
+    for (codename, version) in entries.keys() {
+        let current = newest.entry(codename.as_str()).or_insert(version.as_str());
+        if comparator.compare(version, *current) == Ordering::Greater {
+            *current = version;
+        }
+    }
+    newest
+}
+
+/// Compare two mappings, e.g. before and after a rebuild.  All lists are sorted.
+pub fn diff_mappings(
+    old: &MadisonMapping,
+    new: &MadisonMapping,
+    comparator: &dyn VersionComparator,
+) -> MappingDiff {
+    let mut diff = MappingDiff::default();
+    for (package, new_entries) in new {
+        let old_entries = match old.get(package) {
+            Some(old_entries) => old_entries,
+            None => {
+                diff.added.push(package.clone());
+                continue;
+            }
+        };
+        let old_versions = newest_by_suite(old_entries, comparator);
+        let new_versions = newest_by_suite(new_entries, comparator);
+        let suites: HashSet<_> = old_versions.keys().chain(new_versions.keys()).collect();
+        for suite in suites {
+            let old_version = old_versions.get(suite);
+            let new_version = new_versions.get(suite);
+            if old_version != new_version {
+                diff.changed.push(VersionChange {
+                    package: package.clone(),
+                    suite: suite.to_string(),
+                    old_version: old_version.map(|v| v.to_string()),
+                    new_version: new_version.map(|v| v.to_string()),
+                });
+            }
+        }
+    }
+    diff.removed = old
+        .keys()
+        .filter(|package| !new.contains_key(*package))
+        .cloned()
+        .collect();
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed
+        .sort_by(|c1, c2| (&c1.package, &c1.suite).cmp(&(&c2.package, &c2.suite)));
+    diff
+}
+
+/// Append an `(EOL)` marker to the codename of every record from an end-of-life suite, including
+/// keys of its components like `buster/main`.
+pub fn mark_eol_suites(package_lines: &mut MadisonStructure, eol_suites: &[String]) {
+    for record in package_lines.values_mut().flatten() {
+        let suite = record
+            .codename
+            .split_once('/')
+            .map(|(suite, _)| suite)
+            .unwrap_or(&record.codename);
+        if eol_suites.iter().any(|eol_suite| eol_suite == suite) {
+            record.codename.push_str(" (EOL)");
+        }
+    }
+}
+
+pub fn do_madison(
+    package_lines: &mut MadisonStructure,
+    packages: Vec<String>,
+    columns: &[Column],
+) -> String {
+    let mut output_builder = Builder::default();
+    for package in packages {
+        let merged_vec = if let Some(merged_vec) = package_lines.remove(&package) {
+            merged_vec
+        } else {
+            continue;
+        };
+        for line in merged_vec {
+            output_builder.push_record(line.project(columns));
+        }
+    }
+    format!(
+        "{}\n",
+        output_builder
+            .build()
+            .with(Style::empty().vertical('|'))
+            .to_string()
+            .lines()
+            .map(|line| line.trim())
+            .collect::<Vec<&str>>()
+            .join("\n")
+    )
+}
+
+/// The records for `packages`, in every suite or only in `suite` (which may be a comma-separated
+/// list), ordered by `comparator` as madison orders them.  This is `generate_madison_structure`
+/// with the default filters, for callers embedding madison which want its rows rather than
+/// rendered text; `MadisonConfig::version_comparator` gives the configured comparator.
+pub fn madison_records(
+    madison_mapping: &MadisonMapping,
+    packages: &[String],
+    suite: Option<String>,
+    comparator: &dyn VersionComparator,
+) -> MadisonStructure {
+    generate_madison_structure(
+        madison_mapping,
+        packages,
+        &MadisonFilter::with_suite(suite),
+        comparator,
+    )
+}
+
+/// `packages`' records in `suite` (as for `madison_records`), rendered as madison's table.
+pub fn madison_table(
+    madison_mapping: &MadisonMapping,
+    packages: &[String],
+    suite: Option<String>,
+    comparator: &dyn VersionComparator,
+) -> String {
+    let mut records = madison_records(madison_mapping, packages, suite, comparator);
+    do_madison(&mut records, packages.to_vec(), Column::DEFAULT)
+}
+
+/// Lay rows out as `dak ls` (which serves rmadison) does: each line starts with a space, columns
+/// are separated by ` | `, and all but the last are left-aligned to their widest value.
+pub fn do_madison_rmadison(package_lines: &mut MadisonStructure, packages: Vec<String>) -> String {
+    let rows: Vec<_> = packages
+        .iter()
+        .filter_map(|package| package_lines.remove(package))
+        .flatten()
+        .map(|line| line.project(Column::DEFAULT))
+        .collect();
+    let mut widths = vec![0; Column::DEFAULT.len() - 1];
+    for row in &rows {
+        for (width, field) in widths.iter_mut().zip(row) {
+            *width = (*width).max(field.chars().count());
+        }
+    }
+    let mut output = String::new();
+    for row in rows {
+        let (last, padded) = row.split_last().expect("rows have every column");
+        for (field, width) in padded.iter().zip(&widths) {
+            output.push_str(&format!(" {:<width$} |", field, width = width));
+        }
+        output.push_str(&format!(" {}\n", last));
+    }
+    output
+}
+
+/// Emit `MADISON_<PACKAGE>_<SUITE>=<version>` assignments suitable for `eval` in a shell.
+///
+/// Names are upper-cased with anything that isn't valid in a shell identifier replaced by `_`;
+/// names which collide after that (e.g. `foo-bar` and `foo.bar`, or two versions in one suite)
+/// have `_2`, `_3`, ... appended.
+pub fn do_madison_env(package_lines: &mut MadisonStructure, packages: Vec<String>) -> String {
+    fn sanitize(name: &str) -> String {
+        name.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    let mut seen = HashSet::new();
+    let mut output = String::new();
+    for package in packages {
+        let merged_vec = if let Some(merged_vec) = package_lines.remove(&package) {
+            merged_vec
+        } else {
+            continue;
+        };
+        for line in merged_vec {
+            let base = format!(
+                "MADISON_{}_{}",
+                sanitize(&line.package),
+                sanitize(&line.codename)
+            );
+            let mut name = base.clone();
+            let mut suffix = 1;
+            while !seen.insert(name.clone()) {
+                suffix += 1;
+                name = format!("{}_{}", base, suffix);
+            }
+            // Debian versions never contain a single quote, so this needs no escaping
+            output.push_str(&format!("{}='{}'\n", name, line.version));
+        }
+    }
+    output
+}
+
+/// Condense each package onto one line, e.g. `hello: bookworm=2.10-3 sid=2.10-3`.
+pub fn do_madison_summary(package_lines: &mut MadisonStructure, packages: Vec<String>) -> String {
+    let mut output = String::new();
+    for package in packages {
+        let merged_vec = if let Some(merged_vec) = package_lines.remove(&package) {
+            merged_vec
+        } else {
+            continue;
+        };
+        let suites: Vec<_> = merged_vec
+            .iter()
+            .map(|line| format!("{}={}", line.codename, line.version))
+            .collect();
+        output.push_str(&format!("{}: {}\n", package, suites.join(" ")));
+    }
+    output
+}
+
+/// A record serialized with only the fields of `columns` (in that order), and those fields which
+/// aren't columns.
+struct ProjectedRecord<'a> {
+    record: &'a MadisonOutputRecord,
+    columns: &'a [Column],
+}
+
+impl Serialize for ProjectedRecord<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let record = self.record;
+        let mut map = serializer.serialize_map(None)?;
+        for column in self.columns {
+            match column {
+                Column::Package => map.serialize_entry("package", &record.package)?,
+                Column::Version => map.serialize_entry("version", &record.version)?,
+                Column::Codename => map.serialize_entry("codename", &record.codename)?,
+                Column::Architectures => {
+                    map.serialize_entry("architectures", &record.architectures)?
+                }
+                Column::Size => {
+                    if let Some(size) = record.size {
+                        map.serialize_entry(column.name(), &size)?;
+                    }
+                }
+                Column::InstalledSize => {
+                    if let Some(installed_size) = record.installed_size {
+                        map.serialize_entry(column.name(), &installed_size)?;
+                    }
+                }
+            }
+        }
+        if let Some(binary_count) = record.binary_count {
+            map.serialize_entry("binary_count", &binary_count)?;
+        }
+        if let Some(multi_arch) = &record.multi_arch {
+            map.serialize_entry("multi_arch", multi_arch)?;
+        }
+        map.end()
+    }
+}
+
+/// `records`, each projected onto `columns`.
+fn project_records<'a>(
+    records: &'a [MadisonOutputRecord],
+    columns: &'a [Column],
+) -> Vec<ProjectedRecord<'a>> {
+    records
+        .iter()
+        .map(|record| ProjectedRecord { record, columns })
+        .collect()
+}
+
+/// Serialize each package's records (with the fields of `columns`) as YAML, as a mapping keyed by
+/// package name (in query order).
+pub fn do_madison_yaml(
+    package_lines: &mut MadisonStructure,
+    packages: Vec<String>,
+    columns: &[Column],
+) -> String {
+    let mut output = serde_yaml::Mapping::new();
+    for package in packages {
+        if let Some(merged_vec) = package_lines.remove(&package) {
+            output.insert(
+                serde_yaml::Value::String(package),
+                serde_yaml::to_value(project_records(&merged_vec, columns))
+                    .expect("serialize records"),
+            );
+        }
+    }
+    serde_yaml::to_string(&output).expect("serialize YAML")
+}
+
+/// Serialize the records of `packages` (in query order, with the fields of `columns`) as a single
+/// JSON array, which is `[]` when nothing was found.
+pub fn do_madison_json(
+    package_lines: &mut MadisonStructure,
+    packages: Vec<String>,
+    columns: &[Column],
+) -> String {
+    let records: Vec<_> = packages
+        .iter()
+        .filter_map(|package| package_lines.remove(package))
+        .flatten()
+        .collect();
+    serde_json::to_string(&project_records(&records, columns)).expect("serialize JSON")
+}
+
+/// Write `packages`' records as CSV, with a header row naming `columns`.  Fields are quoted as
+/// needed, so the comma-separated architectures stay within a single field.
+pub fn do_madison_csv(
+    package_lines: &mut MadisonStructure,
+    packages: Vec<String>,
+    columns: &[Column],
+) -> String {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer
+        .write_record(columns.iter().map(Column::name))
+        .expect("write CSV header");
+    for package in packages {
+        if let Some(merged_vec) = package_lines.remove(&package) {
+            for line in merged_vec {
+                writer
+                    .write_record(line.project(columns))
+                    .expect("write CSV record");
+            }
+        }
+    }
+    String::from_utf8(writer.into_inner().expect("flush CSV")).expect("CSV is UTF-8")
+}
+
+/// The ways madison output can be rendered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Env,
+    Summary,
+    Yaml,
+    Json,
+    Csv,
+    Rmadison,
+}
+
+impl OutputFormat {
+    /// Whether this format is for people (and so gets colour and markers like `(EOL)`) rather
+    /// than for machines.
+    pub fn decorated(&self) -> bool {
+        matches!(self, OutputFormat::Table)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "env" => Ok(OutputFormat::Env),
+            "summary" => Ok(OutputFormat::Summary),
+            "yaml" => Ok(OutputFormat::Yaml),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            "rmadison" => Ok(OutputFormat::Rmadison),
+            _ => Err(anyhow::anyhow!(
+                "unknown format {:?} (expected table, env, summary, yaml, json, csv or rmadison)",
+                s
+            )),
+        }
+    }
+}
+
+/// Render `packages` from `package_lines` in `format`.
+///
+/// Whatever each renderer produces, the line-oriented formats end with exactly one newline, and
+/// JSON (a single document on a single line) with none.
+pub fn render_madison(
+    format: OutputFormat,
+    package_lines: &mut MadisonStructure,
+    packages: Vec<String>,
+    columns: &[Column],
+) -> String {
+    let output = match format {
+        OutputFormat::Table => do_madison(package_lines, packages, columns),
+        OutputFormat::Env => do_madison_env(package_lines, packages),
+        OutputFormat::Summary => do_madison_summary(package_lines, packages),
+        OutputFormat::Yaml => do_madison_yaml(package_lines, packages, columns),
+        OutputFormat::Json => do_madison_json(package_lines, packages, columns),
+        OutputFormat::Csv => do_madison_csv(package_lines, packages, columns),
+        OutputFormat::Rmadison => do_madison_rmadison(package_lines, packages),
+    };
+    let content = output.trim_end_matches('\n');
+    match format {
+        OutputFormat::Json => content.to_string(),
+        _ => format!("{}\n", content),
+    }
+}
+
+pub mod key_func {
+    use fapt::system::DownloadedList;
+
+    pub type KeyFunc = dyn Fn(&DownloadedList) -> String + Sync + 'static;
+
+    /// The codename the list was requested under, falling back to the Release file's `Suite` (or
+    /// `Label`) for repos which don't have one, so they don't all share an empty key.
+    pub fn codename(list: &DownloadedList) -> String {
+        codename_or_fallback(
+            &list.release.req.codename,
+            [list.release.suite.as_deref(), list.release.label.as_deref()],
+        )
+    }
+
+    /// `codename`, or (if it's blank) the first of `fallbacks` which isn't.
+    pub(crate) fn codename_or_fallback<'a>(
+        codename: &str,
+        fallbacks: impl IntoIterator<Item = Option<&'a str>>,
+    ) -> String {
+        if !codename.trim().is_empty() {
+            return codename.to_owned();
+        }
+        fallbacks
+            .into_iter()
+            .flatten()
+            .find(|fallback| !fallback.trim().is_empty())
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    pub fn component(list: &DownloadedList) -> String {
+        list.listing.component.to_owned()
+    }
+
+    /// The codename and component together, e.g. `bookworm/contrib`.
+    pub fn codename_component(list: &DownloadedList) -> String {
+        format!("{}/{}", codename(list), component(list))
+    }
+
+    /// Look up one of the key functions in this module by its name.
+    pub fn by_name(name: &str) -> Option<&'static KeyFunc> {
+        match name {
+            "codename" => Some(&codename),
+            "component" => Some(&component),
+            "codename_component" => Some(&codename_component),
+            _ => None,
+        }
+    }
+}
+
+pub mod version {
+    use std::cmp::Ordering;
+    use std::str::FromStr;
+
+    /// An ordering of package versions.
+    pub trait VersionComparator: Sync {
+        fn compare(&self, v1: &str, v2: &str) -> Ordering;
+    }
+
+    /// Debian version ordering (as used by dpkg and apt); the default.
+    pub struct Debian;
+
+    impl VersionComparator for Debian {
+        fn compare(&self, v1: &str, v2: &str) -> Ordering {
+            deb_version::compare_versions(v1, v2)
+        }
+    }
+
+    /// RPM version ordering, for `[epoch:]version[-release]` strings: the epochs are compared
+    /// numerically, then the remainders with rpm's `rpmvercmp` algorithm.
+    pub struct Rpm;
+
+    impl Rpm {
+        fn split_epoch(version: &str) -> (u64, &str) {
+            match version.split_once(':') {
+                Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+                None => (0, version),
+            }
+        }
+
+        /// Compare alternating runs of digits and letters, ignoring other characters except `~`,
+        /// which sorts before anything (even the end of the string).
+        fn rpmvercmp(mut v1: &str, mut v2: &str) -> Ordering {
+            fn is_segment_char(c: char) -> bool {
+                c.is_ascii_alphanumeric() || c == '~'
+            }
+            loop {
+                v1 = v1.trim_start_matches(|c| !is_segment_char(c));
+                v2 = v2.trim_start_matches(|c| !is_segment_char(c));
+                match (v1.strip_prefix('~'), v2.strip_prefix('~')) {
+                    (Some(rest1), Some(rest2)) => {
+                        v1 = rest1;
+                        v2 = rest2;
+                        continue;
+                    }
+                    (Some(_), None) => return Ordering::Less,
+                    (None, Some(_)) => return Ordering::Greater,
+                    (None, None) => {}
+                }
+                if v1.is_empty() || v2.is_empty() {
+                    return v1.len().cmp(&v2.len());
+                }
+                let numeric = v1.starts_with(|c: char| c.is_ascii_digit());
+                let segment_end = |v: &str| {
+                    v.find(|c: char| {
+                        if numeric {
+                            !c.is_ascii_digit()
+                        } else {
+                            !c.is_ascii_alphabetic()
+                        }
+                    })
+                    .unwrap_or(v.len())
+                };
+                let (segment1, rest1) = v1.split_at(segment_end(v1));
+                let (segment2, rest2) = v2.split_at(segment_end(v2));
+                if segment2.is_empty() {
+                    // Segments of different types: numeric ones are newer
+                    return if numeric {
+                        Ordering::Greater
+                    } else {
+                        Ordering::Less
+                    };
+                }
+                let ordering = if numeric {
+                    let segment1 = segment1.trim_start_matches('0');
+                    let segment2 = segment2.trim_start_matches('0');
+                    segment1
+                        .len()
+                        .cmp(&segment2.len())
+                        .then_with(|| segment1.cmp(segment2))
+                } else {
+                    segment1.cmp(segment2)
+                };
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                v1 = rest1;
+                v2 = rest2;
+            }
+        }
+    }
+
+    impl VersionComparator for Rpm {
+        fn compare(&self, v1: &str, v2: &str) -> Ordering {
+            let (epoch1, rest1) = Self::split_epoch(v1);
+            let (epoch2, rest2) = Self::split_epoch(v2);
+            epoch1
+                .cmp(&epoch2)
+                .then_with(|| Self::rpmvercmp(rest1, rest2))
+        }
+    }
+
+    /// A relation between versions, as in a Debian dependency.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Op {
+        Lt,
+        Le,
+        Eq,
+        Ge,
+        Gt,
+    }
+
+    /// A requirement on a version, e.g. `>= 2.0`.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Constraint {
+        pub op: Op,
+        pub version: String,
+    }
+
+    impl Constraint {
+        pub fn satisfied_by(&self, version: &str, comparator: &dyn VersionComparator) -> bool {
+            let ordering = comparator.compare(version, &self.version);
+            match self.op {
+                Op::Lt => ordering == Ordering::Less,
+                Op::Le => ordering != Ordering::Greater,
+                Op::Eq => ordering == Ordering::Equal,
+                Op::Ge => ordering != Ordering::Less,
+                Op::Gt => ordering == Ordering::Greater,
+            }
+        }
+    }
+
+    impl FromStr for Constraint {
+        type Err = anyhow::Error;
+
+        /// Parse an operator (`<<`, `<=`, `=`, `>=` or `>>`, or `<`, `==` or `>`) followed by a
+        /// version, optionally separated by whitespace.
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let s = s.trim();
+            // Two-character operators first, so that `<=` isn't taken for `<`
+            let (op, version) = [
+                ("<<", Op::Lt),
+                ("<=", Op::Le),
+                ("==", Op::Eq),
+                (">=", Op::Ge),
+                (">>", Op::Gt),
+                ("<", Op::Lt),
+                ("=", Op::Eq),
+                (">", Op::Gt),
+            ]
+            .iter()
+            .find_map(|(prefix, op)| s.strip_prefix(prefix).map(|version| (*op, version)))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "version constraint {:?} must start with one of <<, <=, =, >= or >>",
+                    s
+                )
+            })?;
+            let version = version.trim();
+            if version.is_empty() {
+                anyhow::bail!("version constraint {:?} has no version", s);
+            }
+            Ok(Constraint {
+                op,
+                version: version.to_string(),
+            })
+        }
+    }
+
+    /// Look up one of the comparators in this module by its name.
+    pub fn by_name(name: &str) -> Option<&'static dyn VersionComparator> {
+        match name {
+            "debian" => Some(&Debian),
+            "rpm" => Some(&Rpm),
+            _ => None,
+        }
+    }
+}
+
+pub mod madison_cli {
+    use std::cmp::Ordering;
+    use std::io::{BufRead, IsTerminal};
+    use std::str::FromStr;
+    use std::time::{Duration, SystemTime};
+
+    use clap::error::ErrorKind;
+    use clap::{CommandFactory, Parser};
+    use figment::providers::{Format, Toml};
+    use figment::Figment;
+    use owo_colors::OwoColorize;
+    use rocket_prometheus::prometheus::{Registry, TextEncoder};
+    use serde::Deserialize;
+
+    use crate::metrics::MadisonMetrics;
+    use crate::{
+        build_madison_mapping, closest_newer_version, count_records, diverging_from,
+        find_version_inversions, generate_madison_structure, init_system, key_func,
+        mark_eol_suites, matching_packages, reduce_to_latest, reduce_to_origin_suite,
+        render_madison,
+        version::{self, VersionComparator},
+        Column, MadisonConfig, MadisonFilter, MadisonMapping, MadisonStructure, OutputFormat,
+        RegexLimits,
+    };
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum ColorChoice {
+        Auto,
+        Always,
+        Never,
+    }
+
+    impl ColorChoice {
+        fn enabled(&self) -> bool {
+            match self {
+                ColorChoice::Auto => {
+                    std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal()
+                }
+                ColorChoice::Always => true,
+                ColorChoice::Never => false,
+            }
+        }
+    }
+
+    impl FromStr for ColorChoice {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "auto" => Ok(ColorChoice::Auto),
+                "always" => Ok(ColorChoice::Always),
+                "never" => Ok(ColorChoice::Never),
+                _ => Err(anyhow::anyhow!(
+                    "unknown color choice {:?} (expected auto, always or never)",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// Highlight each package's newest version in green, and dim codenames.
+    fn colorize(madison: &mut MadisonStructure, comparator: &dyn VersionComparator) {
+        for records in madison.values_mut() {
+            let newest = records
+                .iter()
+                .map(|record| record.version.clone())
+                .max_by(|v1, v2| comparator.compare(v1, v2));
+            for record in records.iter_mut() {
+                if newest
+                    .as_ref()
+                    .map(|newest| comparator.compare(&record.version, newest) == Ordering::Equal)
+                    == Some(true)
+                {
+                    record.version = record.version.green().to_string();
+                }
+                record.codename = record.codename.dimmed().to_string();
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct CliConfig {
+        global: MadisonConfig,
+    }
+
+    struct CliArgs {
+        packages: Vec<String>,
+        differs_from: Option<String>,
+        newer_than: Option<String>,
+        filter: MadisonFilter,
+        columns: Vec<Column>,
+        color: ColorChoice,
+        format: OutputFormat,
+        origin_suite: bool,
+        latest: bool,
+        inversions: bool,
+        all: bool,
+        metrics_file: Option<String>,
+        regex: bool,
+        fail_on_regex_no_match: bool,
+        count: bool,
+        stdin: bool,
+        key_func: Option<&'static key_func::KeyFunc>,
+    }
+
+    /// Show the versions of packages in the suites configured in Rocket.toml.
+    #[derive(Parser)]
+    #[command(name = "madison", version)]
+    struct Cli {
+        /// Packages to show; `-` reads newline-separated names from stdin.  May be omitted with
+        /// --differs-from, --built-using, --tag or --inversions, which then consider every package
+        #[arg(required_unless_present_any = [
+            "differs_from", "built_using", "tag", "inversions", "stdin", "all"
+        ])]
+        packages: Vec<String>,
+        /// Show every tracked package, in every suite
+        #[arg(long, conflicts_with_all = ["packages", "stdin", "regex"])]
+        all: bool,
+        /// Read newline-separated package names from stdin, as well as any given as arguments
+        #[arg(long)]
+        stdin: bool,
+        /// Treat each package as a regex matching package names
+        #[arg(short, long)]
+        regex: bool,
+        /// With --regex, exit with an error if a regex matches no packages
+        #[arg(long)]
+        fail_on_regex_no_match: bool,
+        /// Only show these suites (comma-separated, and repeatable)
+        #[arg(short, long)]
+        suite: Vec<String>,
+        /// Only show these architectures, which may include `source` (comma-separated, and
+        /// repeatable).  Architecture `all` binaries are shown as each of them, unless `all` is
+        /// one of them
+        #[arg(short, long = "architecture", visible_alias = "arch")]
+        architectures: Vec<String>,
+        /// Treat packages as source package names only, showing the architectures of the binaries
+        /// built from them
+        #[arg(short = 'S', long)]
+        source_only: bool,
+        /// Only show binaries with this priority
+        #[arg(long)]
+        priority: Option<String>,
+        /// Only show Essential binaries
+        #[arg(long)]
+        essential: bool,
+        /// Only show binaries whose Built-Using names this source package
+        #[arg(long)]
+        built_using: Option<String>,
+        /// Only show binaries with this Multi-Arch value (same, foreign or allowed)
+        #[arg(long)]
+        multi_arch: Option<String>,
+        /// Only show binaries carrying this debtag, e.g. role::program
+        #[arg(long)]
+        tag: Option<String>,
+        /// Only show versions starting with this, e.g. `2.` for any 2.x version
+        #[arg(long)]
+        version_prefix: Option<String>,
+        /// Only show versions satisfying this constraint, e.g. `>=2.0` or `<<1.2-3`
+        #[arg(long)]
+        version_filter: Option<version::Constraint>,
+        /// Leave out pre-release versions (those containing `~`, e.g. `2.0~rc1`)
+        #[arg(long)]
+        no_prerelease: bool,
+        /// Only show the next version up from this one
+        #[arg(long)]
+        newer_than: Option<String>,
+        /// Only show packages whose version differs from theirs in this suite
+        #[arg(long)]
+        differs_from: Option<String>,
+        /// Only show the suite each package's newest version reached first (see suite_order)
+        #[arg(long)]
+        origin_suite: bool,
+        /// Only show each package's newest version, in whichever suites have it
+        #[arg(long, conflicts_with = "origin_suite")]
+        latest: bool,
+        /// Report suites with older versions than a suite after them in suite_order, instead
+        #[arg(long)]
+        inversions: bool,
+        /// The columns to show, comma-separated, from package, version, codename, architectures,
+        /// size and installed-size (the last two are not shown by default)
+        #[arg(long)]
+        columns: Option<String>,
+        /// Leave out the architectures column
+        #[arg(long)]
+        no_arches: bool,
+        /// table, env, summary, yaml, json, csv or rmadison
+        #[arg(long, default_value = "table")]
+        format: OutputFormat,
+        /// The same as --format summary
+        #[arg(long)]
+        summary: bool,
+        /// Whether to colour table output: auto, always or never
+        #[arg(long, default_value = "auto")]
+        color: ColorChoice,
+        /// Report the number of records and packages found, after the output
+        #[arg(long)]
+        count: bool,
+        /// What the codename column shows: `codename` (the suite each version is in),
+        /// `component` (main, contrib, ...) or `codename_component` (e.g. bookworm/contrib);
+        /// overrides the configured `key`
+        #[arg(long)]
+        key: Option<String>,
+        /// Write Prometheus metrics to this file once the mapping is built
+        #[arg(long)]
+        metrics_file: Option<String>,
+    }
+
+    fn parse_args(args: impl IntoIterator<Item = String>) -> CliArgs {
+        let cli = Cli::parse_from(args);
+        let mut filter = MadisonFilter {
+            priority: cli.priority,
+            essential: cli.essential,
+            built_using: cli.built_using,
+            multi_arch: cli.multi_arch,
+            tag: cli.tag,
+            version_prefix: cli.version_prefix,
+            version_constraint: cli.version_filter,
+            no_prerelease: cli.no_prerelease,
+            source_only: cli.source_only,
+            ..Default::default()
+        };
+        for suites in &cli.suite {
+            filter.add_suites(suites);
+        }
+        for arches in &cli.architectures {
+            filter.add_arches(arches);
+        }
+        let mut columns = match &cli.columns {
+            Some(columns) => Column::parse_list(columns)
+                .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit()),
+            None => Column::DEFAULT.to_vec(),
+        };
+        if cli.no_arches {
+            columns.retain(|column| *column != Column::Architectures);
+        }
+        CliArgs {
+            packages: cli.packages,
+            differs_from: cli.differs_from,
+            newer_than: cli.newer_than,
+            filter,
+            columns,
+            color: cli.color,
+            format: if cli.summary {
+                OutputFormat::Summary
+            } else {
+                cli.format
+            },
+            origin_suite: cli.origin_suite,
+            latest: cli.latest,
+            inversions: cli.inversions,
+            all: cli.all,
+            metrics_file: cli.metrics_file,
+            regex: cli.regex,
+            fail_on_regex_no_match: cli.fail_on_regex_no_match,
+            count: cli.count,
+            stdin: cli.stdin,
+            key_func: cli.key.map(|name| {
+                key_func::by_name(&name).unwrap_or_else(|| {
+                    Cli::command()
+                        .error(
+                            ErrorKind::InvalidValue,
+                            format!("unknown --key {:?} (expected codename, component or codename_component)", name),
+                        )
+                        .exit()
+                })
+            }),
+        }
+    }
+
+    /// The packages matching any of the regexes `patterns`, in order of the first to match each.
+    /// Errors if a pattern is invalid or (with `fail_on_no_match`) matches nothing.
+    fn regex_packages(
+        madison_mapping: &MadisonMapping,
+        patterns: &[String],
+        fail_on_no_match: bool,
+    ) -> Result<Vec<String>, anyhow::Error> {
+        let mut packages = vec![];
+        for pattern in patterns {
+            let matched = matching_packages(madison_mapping, pattern, RegexLimits::default())?;
+            // Unlike an exact name, which may legitimately be missing, a pattern matching nothing
+            // is likely a mistake
+            if matched.is_empty() && fail_on_no_match {
+                anyhow::bail!("no packages match {:?}", pattern);
+            }
+            for package in matched {
+                if !packages.contains(&package) {
+                    packages.push(package);
+                }
+            }
+        }
+        Ok(packages)
+    }
+
+    /// Every package in `madison_mapping`, in name order.
+    fn all_packages(madison_mapping: &MadisonMapping) -> Vec<String> {
+        let mut packages: Vec<_> = madison_mapping.keys().cloned().collect();
+        packages.sort();
+        packages
+    }
+
+    /// The `--count` trailer for `count_records`' counts.
+    fn count_trailer((records, packages): (usize, usize)) -> String {
+        format!("{} records, {} packages", records, packages)
+    }
+
+    /// Newline-separated package names, ignoring surrounding whitespace and blank lines.
+    fn read_packages(input: impl BufRead) -> Vec<String> {
+        input
+            .lines()
+            .map(|line| line.expect("reading package names"))
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect()
+    }
+
+    /// Write the Prometheus exposition of `metrics` to `path`.
+    fn write_metrics(metrics: MadisonMetrics, path: &str) -> Result<(), anyhow::Error> {
+        let registry = Registry::new();
+        metrics.register_with(&registry)?;
+        let exposition = TextEncoder::new().encode_to_string(&registry.gather())?;
+        std::fs::write(path, exposition)?;
+        Ok(())
+    }
+
+    pub async fn cli(key_func: &key_func::KeyFunc) {
+        let mut args = parse_args(std::env::args());
+        // A `-` package, or --stdin, reads package names from stdin (avoiding argument length
+        // limits for large batches)
+        if args.stdin || args.packages.iter().any(|package| package == "-") {
+            let mut from_stdin = Some(read_packages(std::io::stdin().lock()));
+            let mut packages = vec![];
+            for package in std::mem::take(&mut args.packages) {
+                if package == "-" {
+                    packages.extend(from_stdin.take().unwrap_or_default());
+                } else {
+                    packages.push(package);
+                }
+            }
+            packages.extend(from_stdin.unwrap_or_default());
+            args.packages = packages;
+        }
+        if args.packages.is_empty()
+            && args.differs_from.is_none()
+            && args.filter.built_using.is_none()
+            && args.filter.tag.is_none()
+            && !args.inversions
+            && !args.all
+        {
+            // e.g. --stdin with nothing on stdin
+            Cli::command()
+                .error(ErrorKind::MissingRequiredArgument, "no package names given")
+                .exit()
+        }
+        let config: CliConfig = Figment::new()
+            .merge(Toml::file("Rocket.toml"))
+            .extract()
+            .expect("reading Rocket.toml configuration");
+        let comparator = config
+            .global
+            .version_comparator()
+            .expect("version_comparator");
+        let key_func = match (args.key_func, &config.global.key) {
+            (Some(key_func), _) => key_func,
+            (None, Some(name)) => key_func::by_name(name).expect("unknown key"),
+            (None, None) => key_func,
+        };
+        if config.global.strip_arch_qualifiers && !args.regex {
+            args.packages = args
+                .filter
+                .take_arch_qualifiers(std::mem::take(&mut args.packages));
+        }
+
+        let metrics = MadisonMetrics::new().expect("metrics init");
+        metrics.update_attempts.inc();
+        let system = init_system(&config.global).await.expect("fapt System init");
+        let mut madison_mapping = build_madison_mapping(&system, key_func, &config.global)
+            .expect("build madison mapping");
+        metrics.mapping_rebuilds.inc();
+        metrics.record_rebuild(&madison_mapping, SystemTime::now());
+        if let Some(path) = &args.metrics_file {
+            // Written before any early return, so the dump reflects the build alone
+            write_metrics(metrics.clone(), path).expect("writing --metrics-file");
+        }
+        if let Some(reference_suite) = &args.differs_from {
+            madison_mapping = diverging_from(&madison_mapping, reference_suite, comparator);
+        }
+        if args.inversions {
+            for inversion in
+                find_version_inversions(&madison_mapping, &config.global.suite_order, comparator)
+            {
+                if args.packages.is_empty() || args.packages.contains(&inversion.package) {
+                    println!("{}", inversion);
+                }
+            }
+            return;
+        }
+        let packages = if args.packages.is_empty() {
+            // Without a package, --all, --differs-from, --built-using and --tag report every
+            // package matching
+            all_packages(&madison_mapping)
+        } else if args.regex {
+            regex_packages(
+                &madison_mapping,
+                &args.packages,
+                args.fail_on_regex_no_match,
+            )
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            })
+        } else {
+            args.packages
+        };
+        if let Some(version) = &args.newer_than {
+            // Only show the rows of the next version up from the given one
+            for package in &packages {
+                let newer = closest_newer_version(&madison_mapping, package, version, comparator);
+                if let Some(entries) = madison_mapping.get_mut(package) {
+                    entries.retain(|(_, candidate), _| {
+                        newer
+                            .as_ref()
+                            .map(|(newer, _)| candidate == newer)
+                            .unwrap_or(false)
+                    });
+                }
+            }
+        }
+        let mut filter = args.filter;
+        filter.watched_packages = config.global.watched_packages;
+        filter.suite_aliases = config.global.suite_aliases;
+        let mut madison =
+            generate_madison_structure(&madison_mapping, &packages, &filter, comparator);
+        if args.origin_suite {
+            reduce_to_origin_suite(&mut madison, &config.global.suite_order, comparator);
+        }
+        if args.latest {
+            reduce_to_latest(&mut madison, comparator);
+        }
+        let counts = count_records(&madison);
+        if args.format.decorated() {
+            mark_eol_suites(&mut madison, &config.global.eol_suites);
+            if args.color.enabled() {
+                colorize(&mut madison, comparator);
+            }
+        }
+        print!(
+            "{}",
+            render_madison(args.format, &mut madison, packages, &args.columns)
+        );
+        if args.count {
+            let trailer = count_trailer(counts);
+            // Keep machine-readable output parseable
+            if args.format.decorated() {
+                println!("{}", trailer);
+            } else {
+                eprintln!("{}", trailer);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::tests::{entry, mapping, names};
+        use crate::MadisonEntry;
+
+        /// `parse_args` for `madison` followed by `args`.
+        fn args(args: &[&str]) -> CliArgs {
+            parse_args(
+                std::iter::once("madison")
+                    .chain(args.iter().copied())
+                    .map(str::to_string),
+            )
+        }
+
+        #[test]
+        fn essential_only_shows_essential_binaries() {
+            let mapping = mapping(vec![
+                (
+                    "bash",
+                    "bookworm",
+                    "5.2.15-2+b2",
+                    MadisonEntry {
+                        essential: true,
+                        ..entry(&["amd64"])
+                    },
+                ),
+                ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ]);
+            let args = args(&["--essential", "bash", "hello"]);
+            let madison = generate_madison_structure(
+                &mapping,
+                &args.packages,
+                &args.filter,
+                &version::Debian,
+            );
+            assert_eq!(madison["bash"].len(), 1);
+            assert!(madison["hello"].is_empty());
+        }
+
+        #[test]
+        fn no_arches_drops_the_architectures_field() {
+            let mapping = mapping(vec![("hello", "bookworm", "2.10-3", entry(&["amd64"]))]);
+            let args = args(&["--no-arches", "hello"]);
+            for format in [OutputFormat::Json, OutputFormat::Yaml] {
+                let mut madison = generate_madison_structure(
+                    &mapping,
+                    &args.packages,
+                    &args.filter,
+                    &version::Debian,
+                );
+                let output =
+                    render_madison(format, &mut madison, args.packages.clone(), &args.columns);
+                assert!(output.contains("2.10-3"), "{:?}: {}", format, output);
+                assert!(
+                    !output.contains("architectures"),
+                    "{:?}: {}",
+                    format,
+                    output
+                );
+                assert!(!output.contains("amd64"), "{:?}: {}", format, output);
+            }
+            let mut madison = generate_madison_structure(
+                &mapping,
+                &args.packages,
+                &args.filter,
+                &version::Debian,
+            );
+            let csv = render_madison(
+                OutputFormat::Csv,
+                &mut madison,
+                args.packages.clone(),
+                &args.columns,
+            );
+            assert_eq!(csv, "package,version,codename\nhello,2.10-3,bookworm\n");
+        }
+
+        #[test]
+        fn fail_on_regex_no_match_is_an_error() {
+            let mapping = mapping(vec![
+                ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+                ("hello-traditional", "bookworm", "2.10-3", entry(&["amd64"])),
+            ]);
+            let args = args(&["--regex", "--fail-on-regex-no-match", "^hello", "^nothing"]);
+            assert!(args.fail_on_regex_no_match);
+            let error = regex_packages(&mapping, &args.packages, args.fail_on_regex_no_match)
+                .expect_err("a regex matches nothing");
+            assert_eq!(error.to_string(), r#"no packages match "^nothing""#);
+            // Without the flag, only the matches are shown
+            assert_eq!(
+                regex_packages(&mapping, &args.packages, false).expect("valid regexes"),
+                names(&["hello", "hello-traditional"])
+            );
+        }
+
+        #[test]
+        fn all_shows_every_package() {
+            let mapping = mapping(vec![
+                ("zsh", "bookworm", "5.9-4", entry(&["amd64"])),
+                ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+                ("hello", "sid", "2.10-5", entry(&["amd64"])),
+                ("bash", "sid", "5.2.21-2", entry(&["amd64"])),
+            ]);
+            let args = args(&["--all"]);
+            assert!(args.all && args.packages.is_empty());
+            let packages = all_packages(&mapping);
+            assert_eq!(packages, names(&["bash", "hello", "zsh"]));
+            let mut madison =
+                generate_madison_structure(&mapping, &packages, &args.filter, &version::Debian);
+            let output = render_madison(args.format, &mut madison, packages, &args.columns);
+            assert_eq!(output.lines().count(), 4, "{}", output);
+        }
+
+        #[test]
+        fn count_reports_found_records_and_packages() {
+            let mapping = mapping(vec![
+                ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+                ("hello", "sid", "2.10-5", entry(&["amd64"])),
+                ("zsh", "bookworm", "5.9-4", entry(&["amd64"])),
+            ]);
+            let args = args(&["--count", "hello", "zsh", "missing", "watched"]);
+            assert!(args.count);
+            let madison = generate_madison_structure(
+                &mapping,
+                &args.packages,
+                &MadisonFilter {
+                    watched_packages: ["watched".to_string()].into(),
+                    ..Default::default()
+                },
+                &version::Debian,
+            );
+            // The "not found" placeholder of a watched package isn't counted
+            assert_eq!(madison["watched"].len(), 1);
+            assert_eq!(
+                count_trailer(count_records(&madison)),
+                "3 records, 2 packages"
+            );
+        }
+
+        #[test]
+        fn metrics_file_has_the_build_metrics() {
+            let path = crate::tests::temp_file("metrics.prom", b"");
+            let args = args(&["--metrics-file", &path, "hello"]);
+            assert_eq!(args.metrics_file.as_deref(), Some(path.as_str()));
+
+            let metrics = MadisonMetrics::new().expect("metrics");
+            metrics.update_attempts.inc();
+            metrics.mapping_rebuilds.inc();
+            let mapping = mapping(vec![
+                ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+                ("zsh", "bookworm", "5.9-4", entry(&["amd64"])),
+            ]);
+            metrics.record_rebuild(&mapping, SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+            write_metrics(metrics, &path).expect("write metrics");
+            let exposition = std::fs::read_to_string(&path).expect("read metrics");
+            for sample in [
+                "madison_rs_apt_update_attempts 1",
+                "madison_rs_mapping_rebuilds 1",
+                "madison_rs_mapping_packages 2",
+                "madison_rs_last_rebuild_timestamp_seconds 1000",
+            ] {
+                assert!(
+                    exposition.lines().any(|line| line == sample),
+                    "{}: {}",
+                    sample,
+                    exposition
+                );
+            }
+        }
+
+        /// The `[global]` configuration of a Rocket.toml with `extra` added to the required keys.
+        fn config(extra: &str) -> MadisonConfig {
+            let toml = format!(
+                "[global]\n\
+                 sources_list = \"sources.list\"\n\
+                 extra_key_paths = []\n\
+                 arches = [\"amd64\"]\n\
+                 include_source_arch = true\n\
+                 enable_metrics = false\n\
+                 {}",
+                extra
+            );
+            let config: CliConfig = Figment::new()
+                .merge(Toml::string(&toml))
+                .extract()
+                .expect("valid configuration");
+            config.global
+        }
+
+        #[test]
+        fn update_timeouts_are_independent() {
+            let initial_only = config("initial_update_timeout_secs = 300");
+            assert_eq!(initial_only.initial_update_timeout_secs, Some(300));
+            assert_eq!(initial_only.periodic_update_timeout_secs, None);
+            let periodic_only = config("periodic_update_timeout_secs = 30");
+            assert_eq!(periodic_only.initial_update_timeout_secs, None);
+            assert_eq!(periodic_only.periodic_update_timeout_secs, Some(30));
+        }
+
+        #[test]
+        fn color_never_and_always() {
+            assert!(!args(&["--color", "never", "hello"]).color.enabled());
+            assert!(args(&["--color", "always", "hello"]).color.enabled());
+            assert!("sometimes".parse::<ColorChoice>().is_err());
+
+            let mapping = mapping(vec![
+                ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+                ("hello", "sid", "2.10-5", entry(&["amd64"])),
+            ]);
+            let structure = || {
+                generate_madison_structure(
+                    &mapping,
+                    &names(&["hello"]),
+                    &MadisonFilter::default(),
+                    &version::Debian,
+                )
+            };
+            let render = |madison: &mut MadisonStructure| {
+                render_madison(
+                    OutputFormat::Table,
+                    madison,
+                    names(&["hello"]),
+                    Column::DEFAULT,
+                )
+            };
+            assert!(!render(&mut structure()).contains('\x1b'));
+            let mut colored = structure();
+            colorize(&mut colored, &version::Debian);
+            let colored = render(&mut colored);
+            // Only the newest version is highlighted
+            assert!(colored.contains(&"2.10-5".green().to_string()));
+            assert!(!colored.contains(&"2.10-3".green().to_string()));
+            assert!(colored.contains(&"bookworm".dimmed().to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An entry available as each of `types` (`source`, or an architecture).
+    pub(crate) fn entry(types: &[&str]) -> MadisonEntry {
+        MadisonEntry {
+            types: types
+                .iter()
+                .map(|package_type| match *package_type {
+                    "source" => PackageType::Source,
+                    arch => PackageType::Arch(arch.to_string()),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// A mapping of `(package, codename, version, entry)` rows.
+    pub(crate) fn mapping(rows: Vec<(&str, &str, &str, MadisonEntry)>) -> MadisonMapping {
+        let mut mapping = MadisonMapping::new();
+        for (package, codename, version, entry) in rows {
+            mapping
+                .entry(package.to_string())
+                .or_default()
+                .insert((codename.to_string(), version.to_string()), entry);
+        }
+        mapping
+    }
+
+    pub(crate) fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    /// A configuration tracking amd64 and arm64, with sources.
+    pub(crate) fn test_config() -> MadisonConfig {
+        serde_json::from_value(serde_json::json!({
+            "sources_list": "sources.list",
+            "extra_key_paths": [],
+            "arches": ["amd64", "arm64"],
+            "include_source_arch": true,
+            "enable_metrics": false,
+        }))
+        .expect("valid config")
+    }
+
+    /// What `mapping` shows for `packages` with `filter`, in Debian version order.
+    pub(crate) fn madison_for(
+        mapping: &MadisonMapping,
+        packages: &[&str],
+        filter: &MadisonFilter,
+    ) -> MadisonStructure {
+        generate_madison_structure(mapping, &names(packages), filter, &version::Debian)
+    }
+
+    /// The `(version, codename, architectures)` of each of `package`'s records, in order.
+    pub(crate) fn rows<'a>(
+        structure: &'a MadisonStructure,
+        package: &str,
+    ) -> Vec<(&'a str, &'a str, &'a str)> {
+        structure[package]
+            .iter()
+            .map(|record| {
+                (
+                    record.version.as_str(),
+                    record.codename.as_str(),
+                    record.architectures.as_str(),
+                )
+            })
+            .collect()
+    }
+
+    /// A file named `name` (made unique to this process) in the temporary directory, containing
+    /// `contents`.
+    pub(crate) fn temp_file(name: &str, contents: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("madison-rs-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).expect("write temporary file");
+        path.to_string_lossy().into_owned()
+    }
+
+    /// A Debian repository served over HTTP from a temporary directory, for tests which need fapt
+    /// to really download and parse listings.  Its Release files are unsigned, so sources list
+    /// entries for it need `[untrusted=yes]`.
+    pub(crate) struct FixtureRepository {
+        root: std::path::PathBuf,
+    }
+
+    impl FixtureRepository {
+        pub(crate) fn new(name: &str) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("madison-rs-{}-{}", std::process::id(), name));
+            // Left over from an earlier run which had the same pid
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(&root).expect("create fixture repository");
+            FixtureRepository { root }
+        }
+
+        /// Add a Release file in `dir` (e.g. `dists/bookworm`, or `dist` for a flat repository)
+        /// for `codename`, covering `indices` of `(path under dir, content)`, which are written
+        /// both as they are and gzipped.
+        pub(crate) fn release(
+            self,
+            dir: &str,
+            codename: &str,
+            valid_until: Option<SystemTime>,
+            indices: &[(&str, String)],
+        ) -> Self {
+            use sha2::{Digest, Sha256};
+            use std::collections::BTreeSet;
+            use std::io::Write;
+
+            let dir = self.root.join(dir);
+            let (mut arches, mut components) = (BTreeSet::new(), BTreeSet::new());
+            let mut sha256 = String::new();
+            for (path, content) in indices {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder
+                    .write_all(content.as_bytes())
+                    .expect("compress index");
+                let gzipped = encoder.finish().expect("compress index");
+                for (name, bytes) in [
+                    (path.to_string(), content.as_bytes()),
+                    (format!("{}.gz", path), &gzipped[..]),
+                ] {
+                    let file = dir.join(&name);
+                    std::fs::create_dir_all(file.parent().expect("index directory"))
+                        .expect("create index directory");
+                    std::fs::write(&file, bytes).expect("write index");
+                    sha256.push_str(&format!(
+                        " {:x} {} {}\n",
+                        Sha256::digest(bytes),
+                        bytes.len(),
+                        name
+                    ));
+                }
+                // e.g. main/binary-amd64/Packages; a flat repository's are just Packages
+                if let [component, index_dir, _] = path.split('/').collect::<Vec<_>>()[..] {
+                    components.insert(component.to_string());
+                    if let Some(arch) = index_dir.strip_prefix("binary-") {
+                        arches.insert(arch.to_string());
+                    }
+                }
+            }
+            let join = |set: BTreeSet<String>| set.into_iter().collect::<Vec<_>>().join(" ");
+            let mut release = format!(
+                "Origin: Fixture\nLabel: Fixture\nSuite: {}\nCodename: {}\nDate: {}\n",
+                codename,
+                codename,
+                httpdate::fmt_http_date(SystemTime::now())
+            );
+            if let Some(valid_until) = valid_until {
+                release.push_str(&format!(
+                    "Valid-Until: {}\n",
+                    httpdate::fmt_http_date(valid_until)
+                ));
+            }
+            // A flat repository's Release has no components, and can leave out its architectures
+            for (field, values) in [("Architectures", arches), ("Components", components)] {
+                if !values.is_empty() {
+                    release.push_str(&format!("{}: {}\n", field, join(values)));
+                }
+            }
+            release.push_str(&format!("SHA256:\n{}", sha256));
+            for name in ["Release", "InRelease"] {
+                std::fs::write(dir.join(name), &release).expect("write Release");
+            }
+            self
+        }
+
+        /// Serve the repository on an ephemeral local port, returning its URL.
+        pub(crate) fn serve(self) -> String {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind fixture server");
+            let url = format!("http://{}/", listener.local_addr().expect("local address"));
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let root = self.root.clone();
+                    std::thread::spawn(move || serve_connection(stream, &root));
+                }
+            });
+            url
+        }
+    }
+
+    /// Answer the HTTP requests on `stream` with the files under `root`, until it is closed.
+    fn serve_connection(mut stream: std::net::TcpStream, root: &std::path::Path) {
+        use std::io::{BufReader, Write};
+
+        let mut reader = BufReader::new(stream.try_clone().expect("clone stream"));
+        loop {
+            let mut request = String::new();
+            if reader.read_line(&mut request).unwrap_or(0) == 0 {
+                return;
+            }
+            // Only the request line matters
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap_or(0) > 0 && !header.trim().is_empty() {
+                header.clear();
+            }
+            let mut parts = request.split_whitespace();
+            let method = parts.next().unwrap_or_default();
+            let path = parts
+                .next()
+                .unwrap_or("/")
+                .split('?')
+                .next()
+                .unwrap_or_default();
+            let file = root.join(path.trim_start_matches('/'));
+            let (status, body) = match std::fs::read(&file) {
+                Ok(body) => ("200 OK", body),
+                Err(_) => ("404 Not Found", vec![]),
+            };
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+                status,
+                body.len()
+            );
+            let written = stream
+                .write_all(head.as_bytes())
+                .and_then(|()| match method {
+                    "HEAD" => Ok(()),
+                    _ => stream.write_all(&body),
+                });
+            if written.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// A Packages index of `(name, version, architecture)` binaries.
+    pub(crate) fn packages_index(binaries: &[(&str, &str, &str)]) -> String {
+        binaries
+            .iter()
+            .map(|(name, version, arch)| {
+                format!(
+                    "Package: {name}\n\
+                     Version: {version}\n\
+                     Architecture: {arch}\n\
+                     Maintainer: Fixture Maintainer <fixture@example.com>\n\
+                     Installed-Size: 280\n\
+                     Priority: optional\n\
+                     Section: misc\n\
+                     Filename: pool/main/{name}_{version}_{arch}.deb\n\
+                     Size: 53412\n\
+                     SHA256: {sha256}\n\
+                     Description: {name}, for testing\n\n",
+                    sha256 = "0".repeat(64),
+                )
+            })
+            .collect()
+    }
+
+    fn binary(name: &str, version: &str) -> BinaryStanza {
+        BinaryStanza {
+            name: name.to_string(),
+            version: version.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// The mapping built from `listings` of `(key, architecture, stanzas)`, as
+    /// `build_madison_mapping_of` builds it from a `System`'s listings.
+    fn build(
+        listings: Vec<(&str, Option<&str>, Vec<Stanza>)>,
+        config: &MadisonConfig,
+    ) -> MadisonMapping {
+        let keys_with_sources: HashSet<_> = listings
+            .iter()
+            .filter(|(_, arch, _)| arch.is_none())
+            .map(|(key, _, _)| *key)
+            .collect();
+        merge_rows(
+            listings.into_iter().map(|(key, arch, stanzas)| {
+                let listing = ListingContext {
+                    key,
+                    arch,
+                    has_sources: keys_with_sources.contains(key),
+                };
+                let rows = listing_rows(
+                    stanzas.into_iter().map(Ok),
+                    &listing,
+                    config,
+                    &version::Debian,
+                )
+                .expect("listing rows");
+                let slot = (
+                    key.to_string(),
+                    "main".to_string(),
+                    arch.map(str::to_string),
+                );
+                (slot, rows)
+            }),
+            &version::Debian,
+        )
+    }
+
+    #[test]
+    fn binary_count_counts_binaries_of_source() {
+        // Inferred from the binaries naming it as their source...
+        let stanzas = ["foo", "foo-doc", "libfoo1"]
+            .into_iter()
+            .map(|name| {
+                Stanza::Binary(BinaryStanza {
+                    source: Some("foo".to_string()),
+                    ..binary(name, "1.0-1")
+                })
+            })
+            .collect();
+        let mapping = build(vec![("bookworm", Some("amd64"), stanzas)], &test_config());
+        let madison = madison_for(&mapping, &["foo", "libfoo1"], &MadisonFilter::default());
+        assert_eq!(madison["foo"][0].binary_count, Some(3));
+        assert_eq!(madison["libfoo1"][0].binary_count, None);
+        // ... or taken from a Sources index
+        let source = Stanza::Source(SourceStanza {
+            name: "foo".to_string(),
+            version: "1.0-1".to_string(),
+            binaries: ["foo", "foo-doc", "libfoo1"].map(str::to_string).into(),
+        });
+        let mapping = build(vec![("bookworm", None, vec![source])], &test_config());
+        let madison = madison_for(&mapping, &["foo"], &MadisonFilter::default());
+        assert_eq!(madison["foo"][0].binary_count, Some(3));
+    }
+
+    #[test]
+    fn client_certificates_are_refused() {
+        for (client_cert, client_key) in [(Some("client.pem"), None), (None, Some("client.key"))] {
+            let config = MadisonConfig {
+                client_cert: client_cert.map(str::to_string),
+                client_key: client_key.map(str::to_string),
+                ..test_config()
+            };
+            let error = new_system(&config)
+                .err()
+                .expect("client identity is refused");
+            assert!(error.to_string().contains("mutual TLS"), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_selected_components_are_included() {
+        let url = FixtureRepository::new("components")
+            .release(
+                "dists/bookworm",
+                "bookworm",
+                None,
+                &[
+                    (
+                        "main/binary-amd64/Packages",
+                        packages_index(&[("hello", "2.10-3", "amd64")]),
+                    ),
+                    (
+                        "contrib/binary-amd64/Packages",
+                        packages_index(&[("game-data-packager", "80", "amd64")]),
+                    ),
+                ],
+            )
+            .serve();
+        let sources = format!("deb [untrusted=yes] {} bookworm main contrib\n", url);
+        let config = |components: Option<&[&str]>| MadisonConfig {
+            arches: names(&["amd64"]),
+            components: components.map(names),
+            ..test_config()
+        };
+        let system = init_system_from(&config(None), &sources, &[])
+            .await
+            .expect("fixture system");
+        let packages = |config: &MadisonConfig| {
+            let mapping =
+                build_madison_mapping(&system, &key_func::codename, config).expect("mapping");
+            let mut packages: Vec<_> = mapping.into_keys().collect();
+            packages.sort();
+            packages
+        };
+        assert_eq!(
+            packages(&config(None)),
+            names(&["game-data-packager", "hello"])
+        );
+        assert_eq!(packages(&config(Some(&["main"][..]))), names(&["hello"]));
+        assert_eq!(
+            packages(&config(Some(&["contrib"][..]))),
+            names(&["game-data-packager"])
+        );
+    }
+
+    #[tokio::test]
+    async fn excluded_arches_are_left_out_of_the_mapping() {
+        let url = FixtureRepository::new("excluded-arches")
+            .release(
+                "dists/bookworm",
+                "bookworm",
+                None,
+                &[
+                    (
+                        "main/binary-amd64/Packages",
+                        packages_index(&[("hello", "2.10-3", "amd64")]),
+                    ),
+                    (
+                        "main/binary-i386/Packages",
+                        packages_index(&[
+                            ("hello", "2.10-3", "i386"),
+                            ("libc6-i386", "2.36-9", "i386"),
+                        ]),
+                    ),
+                ],
+            )
+            .serve();
+        let sources = format!("deb [untrusted=yes] {} bookworm main\n", url);
+        let tracking = MadisonConfig {
+            arches: names(&["amd64", "i386"]),
+            ..test_config()
+        };
+        let excluding = MadisonConfig {
+            arches: names(&["amd64", "i386"]),
+            exclude_arches: names(&["i386"]),
+            ..test_config()
+        };
+        assert_eq!(excluding.tracked_arches(), names(&["amd64"]));
+        // i386's index is downloaded here, as it would be for a mirror which provides it however
+        // the configuration changes, so it's the mapping build which must leave it out
+        let system = init_system_from(&tracking, &sources, &[])
+            .await
+            .expect("fixture system");
+        let types = |config: &MadisonConfig| {
+            let mapping =
+                build_madison_mapping(&system, &key_func::codename, config).expect("mapping");
+            let mut types: Vec<_> = mapping
+                .values()
+                .flat_map(HashMap::values)
+                .flat_map(|entry| entry.types.iter().map(ToString::to_string))
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect();
+            types.sort();
+            (types, mapping.contains_key("libc6-i386"))
+        };
+        assert_eq!(
+            types(&tracking),
+            (names(&["amd64", "i386", "source"]), true)
+        );
+        assert_eq!(types(&excluding), (names(&["amd64", "source"]), false));
+    }
+
+    #[test]
+    fn gzipped_key_files_are_decompressed() {
+        use std::io::Write;
+
+        let key = b"-----BEGIN PGP PUBLIC KEY BLOCK-----".to_vec();
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&key).expect("compress key");
+        let gzipped = temp_file("keyring.gpg.gz", &encoder.finish().expect("compress key"));
+        assert_eq!(read_key_file(&gzipped).expect("read gzipped key"), key);
+        let plain = temp_file("keyring.gpg", &key);
+        assert_eq!(read_key_file(&plain).expect("read plain key"), key);
+    }
+
+    #[test]
+    fn key_loading_tolerates_unreadable_files() {
+        let good = temp_file("good-key.gpg", b"key material");
+        let paths = vec![good, "/nonexistent/madison-rs/key.gpg".to_string()];
+        let mut loaded = vec![];
+        load_key_files(&paths, |path| {
+            loaded.push(read_key_file(path)?);
+            Ok(())
+        })
+        .expect("one key file loads");
+        assert_eq!(loaded, [b"key material".to_vec()]);
+        // ... but it's an error if none do
+        assert!(load_key_files(&paths[1..], |path| read_key_file(path).map(drop)).is_err());
+        assert!(load_key_files(&[], |_| unreachable!()).is_ok());
+    }
+
+    #[test]
+    fn mirrors_are_rewritten_to_their_own_fallbacks() {
+        let config = MadisonConfig {
+            fallback_mirrors: HashMap::from([
+                (
+                    "http://deb.debian.org/debian/".to_string(),
+                    names(&["http://mirror-a/debian", "http://mirror-b/debian/"]),
+                ),
+                (
+                    "http://security.debian.org/debian-security".to_string(),
+                    names(&["http://mirror-a/debian-security"]),
+                ),
+            ]),
+            ..test_config()
+        };
+        assert_eq!(fallback_count(&config), 2);
+        let urls = |fallback| {
+            let mut urls = names(&[
+                "http://deb.debian.org/debian",
+                "http://security.debian.org/debian-security/",
+                "http://example.com/other",
+            ]);
+            rewrite_mirrors(&mut urls, &config.fallback_mirrors, fallback);
+            urls
+        };
+        assert_eq!(
+            urls(0),
+            [
+                "http://mirror-a/debian/",
+                "http://mirror-a/debian-security/",
+                "http://example.com/other"
+            ]
+        );
+        // Mirrors without a second fallback stay on their own
+        assert_eq!(
+            urls(1),
+            [
+                "http://mirror-b/debian/",
+                "http://security.debian.org/debian-security/",
+                "http://example.com/other"
+            ]
+        );
+    }
+
+    #[test]
+    fn source_is_not_an_architecture() {
+        let mapping = build(
+            vec![(
+                "bookworm",
+                Some("source"),
+                vec![Stanza::Binary(binary("hello", "2.10-3"))],
+            )],
+            &test_config(),
+        );
+        let types = &mapping["hello"][&("bookworm".to_string(), "2.10-3".to_string())].types;
+        assert_eq!(types, &HashSet::from([PackageType::Source]));
+    }
+
+    #[test]
+    fn built_using_finds_statically_linked_binaries() {
+        assert_eq!(
+            relationship_names("foo (= 1.0), libbar-dev (= 2.3-1)"),
+            HashSet::from(["foo".to_string(), "libbar-dev".to_string()])
+        );
+        let stanzas = vec![
+            Stanza::Binary(BinaryStanza {
+                source: Some("tools".to_string()),
+                built_using: relationship_names("foo (= 1.0)"),
+                ..binary("static-tool", "1.0-1")
+            }),
+            Stanza::Binary(BinaryStanza {
+                source: Some("tools".to_string()),
+                ..binary("dynamic-tool", "1.0-1")
+            }),
+        ];
+        let mapping = build(vec![("bookworm", Some("amd64"), stanzas)], &test_config());
+        let madison = madison_for(
+            &mapping,
+            &["static-tool", "dynamic-tool", "tools"],
+            &MadisonFilter {
+                built_using: Some("foo".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            rows(&madison, "static-tool"),
+            [("1.0-1", "bookworm", "amd64")]
+        );
+        assert!(madison["dynamic-tool"].is_empty());
+        assert!(madison["tools"].is_empty());
+    }
+
+    #[test]
+    fn version_inversions_are_reported() {
+        let mapping = mapping(vec![
+            (
+                "hello",
+                "jammy-security",
+                "2.10-2ubuntu1",
+                entry(&["amd64"]),
+            ),
+            ("hello", "jammy-updates", "2.10-2ubuntu2", entry(&["amd64"])),
+            ("zsh", "jammy-security", "5.8.1-1ubuntu1", entry(&["amd64"])),
+            ("zsh", "jammy-updates", "5.8.1-1", entry(&["amd64"])),
+        ]);
+        let suite_order = names(&["jammy-security", "jammy-updates"]);
+        assert_eq!(
+            find_version_inversions(&mapping, &suite_order, &version::Debian),
+            [VersionInversion {
+                package: "hello".to_string(),
+                suite: "jammy-security".to_string(),
+                version: "2.10-2ubuntu1".to_string(),
+                later_suite: "jammy-updates".to_string(),
+                later_version: "2.10-2ubuntu2".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn multi_arch_is_captured_and_filtered() {
+        let stanzas = vec![
+            Stanza::Binary(BinaryStanza {
+                multi_arch: Some("same".to_string()),
+                ..binary("libfoo1", "1.0-1")
+            }),
+            Stanza::Binary(BinaryStanza {
+                multi_arch: Some("foreign".to_string()),
+                ..binary("foo-bin", "1.0-1")
+            }),
+            Stanza::Binary(binary("foo-doc", "1.0-1")),
+        ];
+        let mapping = build(vec![("bookworm", Some("amd64"), stanzas)], &test_config());
+        let packages = names(&["libfoo1", "foo-bin", "foo-doc"]);
+        let madison = generate_madison_structure(
+            &mapping,
+            &packages,
+            &MadisonFilter::default(),
+            &version::Debian,
+        );
+        assert_eq!(madison["libfoo1"][0].multi_arch.as_deref(), Some("same"));
+        assert_eq!(madison["foo-doc"][0].multi_arch, None);
+        let madison = generate_madison_structure(
+            &mapping,
+            &packages,
+            &MadisonFilter {
+                multi_arch: Some("same".to_string()),
+                ..Default::default()
+            },
+            &version::Debian,
+        );
+        assert_eq!(
+            rows(&madison, "libfoo1"),
+            [("1.0-1", "bookworm", "source, amd64")]
+        );
+        assert!(madison["foo-bin"].is_empty());
+        assert!(madison["foo-doc"].is_empty());
+    }
+
+    #[test]
+    fn blank_codenames_fall_back_to_suite_then_label() {
+        use key_func::codename_or_fallback;
+
+        assert_eq!(
+            codename_or_fallback("bookworm", [Some("stable"), Some("Debian")]),
+            "bookworm"
+        );
+        assert_eq!(
+            codename_or_fallback("", [Some("internal"), Some("Internal Repo")]),
+            "internal"
+        );
+        assert_eq!(
+            codename_or_fallback(" ", [None, Some("Internal Repo")]),
+            "Internal Repo"
+        );
+        assert_eq!(codename_or_fallback("", [Some(""), None]), "");
+    }
+
+    #[tokio::test]
+    async fn flat_repositories_are_tracked_under_the_flat_suite() {
+        assert!(is_flat_suite("./"));
+        assert!(!is_flat_suite("bookworm"));
+        let url = FixtureRepository::new("flat")
+            .release(
+                "dist",
+                "internal",
+                None,
+                &[(
+                    "Packages",
+                    packages_index(&[("internal-tools", "1.2-1", "all")]),
+                )],
+            )
+            .serve();
+        let config = |flat_suite_name: Option<&str>| MadisonConfig {
+            arches: names(&["amd64"]),
+            flat_suite_name: flat_suite_name.map(str::to_string),
+            ..test_config()
+        };
+        let system = init_system_from(
+            &config(None),
+            &format!("deb [untrusted=yes] {} dist/\n", url),
+            &[],
+        )
+        .await
+        .expect("fixture system");
+        let listings = system.listings().expect("listings");
+        assert!(!listings.is_empty());
+        for list in &listings {
+            assert!(is_flat(list));
+            assert_eq!(
+                listing_key(list, &key_func::codename, &config(None)),
+                "flat"
+            );
+        }
+
+        let config = config(Some("internal"));
+        let mapping =
+            build_madison_mapping(&system, &key_func::codename, &config).expect("mapping");
+        let madison = madison_for(&mapping, &["internal-tools"], &MadisonFilter::default());
+        assert_eq!(
+            rows(&madison, "internal-tools"),
+            [("1.2-1", "internal", "source, all")]
+        );
+    }
+
+    #[test]
+    fn version_prefix_matches_versions_starting_with_it() {
+        let mapping = mapping(vec![
+            ("hello", "bullseye", "1.9-1", entry(&["amd64"])),
+            ("hello", "bookworm", "2.0-1", entry(&["amd64"])),
+            ("hello", "trixie", "1:2.5-1", entry(&["amd64"])),
+            ("hello", "sid", "12.0-1", entry(&["amd64"])),
+        ]);
+        let madison = madison_for(
+            &mapping,
+            &["hello"],
+            &MadisonFilter {
+                version_prefix: Some("2.".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            rows(&madison, "hello"),
+            [
+                ("2.0-1", "bookworm", "amd64"),
+                ("1:2.5-1", "trixie", "amd64")
+            ]
+        );
+    }
+
+    /// A sources list for a served fixture repository with `hello` in bookworm (whose Release
+    /// expired yesterday), trixie (whose expires tomorrow) and sid (whose never does).
+    pub(crate) fn expiring_sources(name: &str) -> String {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let hello = || {
+            vec![(
+                "main/binary-amd64/Packages",
+                packages_index(&[("hello", "2.10-3", "amd64")]),
+            )]
+        };
+        let url = FixtureRepository::new(name)
+            .release(
+                "dists/bookworm",
+                "bookworm",
+                Some(SystemTime::now() - day),
+                &hello(),
+            )
+            .release(
+                "dists/trixie",
+                "trixie",
+                Some(SystemTime::now() + day),
+                &hello(),
+            )
+            .release("dists/sid", "sid", None, &hello())
+            .serve();
+        ["bookworm", "trixie", "sid"]
+            .map(|suite| format!("deb [untrusted=yes] {} {} main\n", url, suite))
+            .concat()
+    }
+
+    #[tokio::test]
+    async fn releases_past_valid_until_are_expired() {
+        let config = |refuse_expired_suites| MadisonConfig {
+            arches: names(&["amd64"]),
+            refuse_expired_suites,
+            ..test_config()
+        };
+        let system = init_system_from(&config(false), &expiring_sources("expiring"), &[])
+            .await
+            .expect("fixture system");
+        assert_eq!(
+            expired_suites(&system, &key_func::codename, &config(false)).expect("expired"),
+            names(&["bookworm"])
+        );
+        let suites = |config: &MadisonConfig| {
+            let mapping =
+                build_madison_mapping(&system, &key_func::codename, config).expect("mapping");
+            let madison = madison_for(&mapping, &["hello"], &MadisonFilter::default());
+            let mut suites: Vec<_> = rows(&madison, "hello")
+                .into_iter()
+                .map(|(_, suite, _)| suite.to_string())
+                .collect();
+            suites.sort();
+            suites
+        };
+        // Expired suites are only warned about, unless refuse_expired_suites is set
+        assert_eq!(
+            suites(&config(false)),
+            names(&["bookworm", "sid", "trixie"])
+        );
+        assert_eq!(suites(&config(true)), names(&["sid", "trixie"]));
+    }
+
+    #[tokio::test]
+    async fn mappings_build_from_inline_sources_and_keys() {
+        let url = FixtureRepository::new("inline-sources")
+            .release(
+                "dists/bookworm",
+                "bookworm",
+                None,
+                &[(
+                    "main/binary-amd64/Packages",
+                    packages_index(&[("hello", "2.10-3", "amd64")]),
+                )],
+            )
+            .serve();
+        let config = MadisonConfig {
+            arches: names(&["amd64"]),
+            ..test_config()
+        };
+        let sources = format!("deb [untrusted=yes] {} bookworm main\n", url);
+        let mapping = build_madison_mapping_from(&config, &sources, &[], &key_func::codename)
+            .await
+            .expect("build from inline sources");
+        let madison = madison_for(&mapping, &["hello"], &MadisonFilter::default());
+        assert_eq!(
+            rows(&madison, "hello"),
+            [("2.10-3", "bookworm", "source, amd64")]
+        );
+        // The keys are given directly, so a bad one is an error rather than warned about
+        assert!(init_system_from(&config, "", &[b"not a key".as_slice()])
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn tag_filter_only_shows_tagged_binaries() {
+        let stanzas = vec![
+            Stanza::Binary(BinaryStanza {
+                tags: ["role::program", "interface::commandline"]
+                    .map(str::to_string)
+                    .into(),
+                ..binary("hello", "2.10-3")
+            }),
+            Stanza::Binary(BinaryStanza {
+                tags: ["role::documentation"].map(str::to_string).into(),
+                ..binary("hello-doc", "2.10-3")
+            }),
+            Stanza::Binary(binary("hello-untagged", "2.10-3")),
+        ];
+        let mapping = build(vec![("bookworm", Some("amd64"), stanzas)], &test_config());
+        let madison = madison_for(
+            &mapping,
+            &["hello", "hello-doc", "hello-untagged"],
+            &MadisonFilter {
+                tag: Some("role::program".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            rows(&madison, "hello"),
+            [("2.10-3", "bookworm", "source, amd64")]
+        );
+        assert!(madison["hello-doc"].is_empty());
+        assert!(madison["hello-untagged"].is_empty());
+    }
+
+    #[test]
+    fn custom_comparators_order_the_rows() {
+        /// Orders versions by length, then as text
+        struct Length;
+
+        impl VersionComparator for Length {
+            fn compare(&self, v1: &str, v2: &str) -> Ordering {
+                v1.len().cmp(&v2.len()).then_with(|| v1.cmp(v2))
+            }
+        }
+
+        let mapping = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("hello", "sid", "10.0-1", entry(&["amd64"])),
+        ]);
+        let versions = |comparator: &dyn VersionComparator| {
+            let madison = generate_madison_structure(
+                &mapping,
+                &names(&["hello"]),
+                &MadisonFilter::default(),
+                comparator,
+            );
+            madison["hello"]
+                .iter()
+                .map(|record| record.version.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(versions(&version::Debian), ["2.10-3", "10.0-1"]);
+        assert_eq!(versions(&Length), ["10.0-1", "2.10-3"]);
+        let config = MadisonConfig {
+            version_comparator: Some("rpm".to_string()),
+            ..test_config()
+        };
+        assert!(config.version_comparator().is_ok());
+        let config = MadisonConfig {
+            version_comparator: Some("semver".to_string()),
+            ..test_config()
+        };
+        assert!(config.version_comparator().is_err());
+    }
+
+    #[test]
+    fn packages_index_has_a_stanza_per_binary() {
+        let mapping = mapping(vec![
+            (
+                "hello",
+                "bookworm",
+                "2.10-3",
+                MadisonEntry {
+                    priority: Some("optional".to_string()),
+                    ..entry(&["source", "amd64", "arm64"])
+                },
+            ),
+            ("hello-doc", "bookworm", "2.10-3", entry(&["all"])),
+            ("hello", "sid", "2.10-5", entry(&["amd64"])),
+        ]);
+        assert_eq!(
+            packages_index(&mapping, "bookworm", Some("amd64"), &version::Debian),
+            "Package: hello\nVersion: 2.10-3\nArchitecture: amd64\nPriority: optional\n\n\
+             Package: hello-doc\nVersion: 2.10-3\nArchitecture: all\n\n"
+        );
+        let all_arches = packages_index(&mapping, "bookworm", None, &version::Debian);
+        assert_eq!(all_arches.matches("Package: hello\n").count(), 2);
+        assert!(!all_arches.contains("2.10-5"));
+    }
+
+    #[test]
+    fn a_package_in_two_components_has_two_rows() {
+        // As keyed by key_func::codename_component
+        let mapping = build(
+            vec![
+                (
+                    "bookworm/main",
+                    Some("amd64"),
+                    vec![Stanza::Binary(binary("firmware", "1.0-1"))],
+                ),
+                (
+                    "bookworm/non-free",
+                    Some("amd64"),
+                    vec![Stanza::Binary(binary("firmware", "1.0-1+nonfree"))],
+                ),
+            ],
+            &test_config(),
+        );
+        let madison = madison_for(
+            &mapping,
+            &["firmware"],
+            &MadisonFilter::with_suite(Some("bookworm".to_string())),
+        );
+        assert_eq!(
+            rows(&madison, "firmware"),
+            [
+                ("1.0-1", "bookworm/main", "source, amd64"),
+                ("1.0-1+nonfree", "bookworm/non-free", "source, amd64")
+            ]
+        );
+    }
+
+    #[test]
+    fn size_columns_are_only_shown_when_selected() {
+        let stanzas = vec![Stanza::Binary(BinaryStanza {
+            size: Some(53_412),
+            installed_size: Some(280),
+            ..binary("hello", "2.10-3")
+        })];
+        let mapping = build(vec![("bookworm", Some("amd64"), stanzas)], &test_config());
+        let structure = || madison_for(&mapping, &["hello"], &MadisonFilter::default());
+        assert_eq!(
+            do_madison(&mut structure(), names(&["hello"]), Column::DEFAULT),
+            "hello | 2.10-3 | bookworm | source, amd64\n"
+        );
+        let columns = Column::parse_list("version,size,installed-size").expect("valid columns");
+        assert_eq!(
+            do_madison_csv(&mut structure(), names(&["hello"]), &columns),
+            "version,size,installed-size\n2.10-3,53412,280\n"
+        );
+        assert_eq!(
+            do_madison_json(&mut structure(), names(&["hello"]), &columns),
+            r#"[{"version":"2.10-3","size":53412,"installed-size":280,"binary_count":1}]"#
+        );
+        assert_eq!(
+            do_madison_json(&mut structure(), names(&["hello"]), Column::DEFAULT),
+            r#"[{"package":"hello","version":"2.10-3","codename":"bookworm","architectures":"source, amd64","binary_count":1}]"#
+        );
+    }
+
+    #[test]
+    fn arch_all_binaries_are_shown_once() {
+        let doc = || {
+            vec![Stanza::Binary(BinaryStanza {
+                source: Some("hello".to_string()),
+                arch_all: true,
+                ..binary("hello-doc", "2.10-3")
+            })]
+        };
+        let mapping = build(
+            vec![
+                ("bookworm", Some("amd64"), doc()),
+                ("bookworm", Some("arm64"), doc()),
+            ],
+            &test_config(),
+        );
+        let madison = madison_for(&mapping, &["hello-doc", "hello"], &MadisonFilter::default());
+        assert_eq!(rows(&madison, "hello-doc"), [("2.10-3", "bookworm", "all")]);
+        assert_eq!(rows(&madison, "hello"), [("2.10-3", "bookworm", "source")]);
+
+        // When arches are requested, they're shown under each of them, even those without an index
+        // of their own...
+        let mapping = build(vec![("bookworm", Some("amd64"), doc())], &test_config());
+        let arches = |arches: &[&str]| MadisonFilter {
+            arches: Some(arches.iter().map(|arch| arch.to_string()).collect()),
+            ..Default::default()
+        };
+        let madison = |filter| madison_for(&mapping, &["hello-doc"], &filter);
+        assert_eq!(
+            rows(&madison(arches(&["amd64", "arm64"])), "hello-doc"),
+            [("2.10-3", "bookworm", "amd64, arm64")]
+        );
+        // ...unless all itself is asked for
+        assert_eq!(
+            rows(&madison(arches(&["all", "arm64"])), "hello-doc"),
+            [("2.10-3", "bookworm", "all")]
+        );
+    }
+
+    #[test]
+    fn no_prerelease_drops_tilde_versions() {
+        let mapping = mapping(vec![
+            ("foo", "experimental", "2.0~rc1-1", entry(&["amd64"])),
+            ("foo", "sid", "2.0-1", entry(&["amd64"])),
+            (
+                "foo",
+                "bookworm-backports",
+                "2.0-1~bpo12+1",
+                entry(&["amd64"]),
+            ),
+        ]);
+        let filter = |no_prerelease| MadisonFilter {
+            no_prerelease,
+            ..Default::default()
+        };
+        let madison = madison_for(&mapping, &["foo"], &filter(true));
+        assert_eq!(rows(&madison, "foo"), [("2.0-1", "sid", "amd64")]);
+        let madison = madison_for(&mapping, &["foo"], &filter(false));
+        assert_eq!(madison["foo"].len(), 3);
+    }
+
+    #[test]
+    fn binnmus_keep_the_sources_version() {
+        let source = Stanza::Source(SourceStanza {
+            name: "hello".to_string(),
+            version: "1.0".to_string(),
+            binaries: ["hello".to_string()].into(),
+        });
+        let binnmu = Stanza::Binary(BinaryStanza {
+            source: Some("hello".to_string()),
+            ..binary("hello", "1.0+b1")
+        });
+        let mapping = build(
+            vec![
+                ("bookworm", None, vec![source]),
+                ("bookworm", Some("amd64"), vec![binnmu]),
+            ],
+            &test_config(),
+        );
+        let madison = madison_for(&mapping, &["hello"], &MadisonFilter::default());
+        assert_eq!(
+            rows(&madison, "hello"),
+            [
+                ("1.0", "bookworm", "source"),
+                ("1.0+b1", "bookworm", "amd64")
+            ]
+        );
+    }
+
+    #[test]
+    fn embedding_api_orders_by_the_given_comparator() {
+        let mapping = mapping(vec![
+            ("foo", "bookworm", "1.0~rc1", entry(&["amd64"])),
+            ("foo", "sid", "1.0", entry(&["amd64"])),
+            ("foo", "trixie", "1.0.1", entry(&["amd64"])),
+        ]);
+        let packages = names(&["foo"]);
+        let versions = |comparator: &dyn VersionComparator| {
+            madison_records(&mapping, &packages, None, comparator)["foo"]
+                .iter()
+                .map(|record| record.version.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(versions(&version::Debian), ["1.0~rc1", "1.0", "1.0.1"]);
+        assert_eq!(
+            madison_table(&mapping, &packages, Some("sid".to_string()), &version::Rpm),
+            "foo | 1.0 | sid | amd64\n"
+        );
+        /// Orders versions newest first
+        struct Newest;
+
+        impl VersionComparator for Newest {
+            fn compare(&self, v1: &str, v2: &str) -> Ordering {
+                version::Debian.compare(v2, v1)
+            }
+        }
+        assert_eq!(versions(&Newest), ["1.0.1", "1.0", "1.0~rc1"]);
+    }
+
+    #[test]
+    fn listing_fingerprints_follow_their_index_hashes() {
+        assert_eq!(
+            listing_index_path("main", Some("amd64")),
+            "main/binary-amd64/Packages"
+        );
+        assert_eq!(
+            listing_index_path("contrib", None),
+            "contrib/source/Sources"
+        );
+        assert_eq!(listing_index_path("", Some("amd64")), "Packages");
+
+        const OLD: [u8; 32] = [1; 32];
+        const NEW: [u8; 32] = [2; 32];
+        let release = |amd64: &'static [u8; 32], arm64: &'static [u8; 32]| {
+            vec![
+                ("main/binary-amd64/Packages", 100, amd64),
+                ("main/binary-amd64/Packages.xz", 20, amd64),
+                ("main/binary-amd64/Packages.diff/Index", 5, &[3; 32]),
+                ("main/binary-arm64/Packages", 90, arm64),
+            ]
+        };
+        let amd64 = |contents: Vec<_>| index_hash(contents, "main/binary-amd64/Packages");
+        // Another listing changing (or the Release being re-signed) leaves amd64's unchanged...
+        assert_eq!(amd64(release(&OLD, &OLD)), amd64(release(&OLD, &NEW)));
+        // ... but not its own
+        assert_ne!(amd64(release(&OLD, &OLD)), amd64(release(&NEW, &OLD)));
+    }
+
+    #[test]
+    fn unindexed_binaries_are_not_queryable() {
+        let stanzas = vec![Stanza::Binary(BinaryStanza {
+            source: Some("hello-src".to_string()),
+            ..binary("hello", "2.10-3")
+        })];
+        let config = MadisonConfig {
+            index_binaries: false,
+            ..test_config()
+        };
+        let mapping = build(vec![("bookworm", Some("amd64"), stanzas)], &config);
+        assert!(!mapping.contains_key("hello"));
+        let madison = madison_for(&mapping, &["hello", "hello-src"], &MadisonFilter::default());
+        assert!(!madison.contains_key("hello"));
+        assert_eq!(
+            rows(&madison, "hello-src"),
+            [("2.10-3", "bookworm", "source, amd64")]
+        );
+    }
+
+    #[test]
+    fn mirrors_of_one_suite_are_merged_by_max_version() {
+        let mirrors = [
+            ("bookworm", "http://deb.debian.org/debian/"),
+            ("bookworm", "http://cdn.example.com/debian/"),
+            ("sid", "http://deb.debian.org/debian/"),
+            ("sid", "http://deb.debian.org/debian/"),
+        ];
+        assert_eq!(
+            suites_with_several_mirrors(
+                mirrors.map(|(suite, mirror)| (suite.to_string(), mirror.to_string()))
+            ),
+            HashMap::from([(
+                "bookworm".to_string(),
+                names(&[
+                    "http://cdn.example.com/debian/",
+                    "http://deb.debian.org/debian/"
+                ])
+            )])
+        );
+
+        // Each mirror's bookworm/main/amd64 listing, one of them behind the other
+        let config = test_config();
+        let listing = ListingContext {
+            key: "bookworm",
+            arch: Some("amd64"),
+            has_sources: false,
+        };
+        let mirror_rows = |hello_version: &str| {
+            let slot = (
+                "bookworm".to_string(),
+                "main".to_string(),
+                Some("amd64".to_string()),
+            );
+            let stanzas = [Ok(Stanza::Binary(binary("hello", hello_version)))];
+            let rows = listing_rows(stanzas, &listing, &config, &version::Debian);
+            (slot, rows.expect("listing rows"))
+        };
+        let mapping = merge_rows(
+            [mirror_rows("2.10-3"), mirror_rows("2.10-2")],
+            &version::Debian,
+        );
+        let madison = madison_for(&mapping, &["hello"], &MadisonFilter::default());
+        assert_eq!(
+            rows(&madison, "hello"),
+            [("2.10-3", "bookworm", "source, amd64")]
+        );
+    }
+
+    #[test]
+    fn suite_filter_takes_lists_aliases_and_any_case() {
+        let mapping = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("hello", "bookworm/contrib", "2.10-3", entry(&["amd64"])),
+            ("hello", "trixie", "2.10-3", entry(&["amd64"])),
+            ("hello", "sid", "2.10-5", entry(&["amd64"])),
+        ]);
+        let suites = |suite: &str| {
+            let filter = MadisonFilter {
+                suite_aliases: HashMap::from([("stable".to_string(), "bookworm".to_string())]),
+                ..MadisonFilter::with_suite(Some(suite.to_string()))
+            };
+            rows(&madison_for(&mapping, &["hello"], &filter), "hello")
+                .into_iter()
+                .map(|(_, codename, _)| codename.to_string())
+                .collect::<Vec<_>>()
+        };
+        // Codenames and aliases match in any case, and also match keys of their components
+        for suite in ["bookworm", "BOOKWORM", "stable", "Stable", "STABLE"] {
+            assert_eq!(
+                suites(suite),
+                names(&["bookworm", "bookworm/contrib"]),
+                "{}",
+                suite
+            );
+        }
+        assert_eq!(
+            suites("stable, sid"),
+            names(&["bookworm", "bookworm/contrib", "sid"])
+        );
+        assert_eq!(suites("trixie,sid"), names(&["trixie", "sid"]));
+    }
+
+    #[test]
+    fn equal_versions_are_ordered_by_codename_then_text() {
+        let by_codename = mapping(vec![
+            ("hello", "trixie", "2.10-3", entry(&["amd64"])),
+            ("hello", "sid", "2.10-5", entry(&["amd64"])),
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("hello", "bookworm-backports", "2.10-3", entry(&["amd64"])),
+        ]);
+        let madison = madison_for(&by_codename, &["hello"], &MadisonFilter::default());
+        assert_eq!(
+            rows(&madison, "hello"),
+            [
+                ("2.10-3", "bookworm", "amd64"),
+                ("2.10-3", "bookworm-backports", "amd64"),
+                ("2.10-3", "trixie", "amd64"),
+                ("2.10-5", "sid", "amd64"),
+            ]
+        );
+        // Versions spelled differently are ordered by their text; the order must be total, so
+        // mustn't depend on each mapping's (random) iteration order
+        for _ in 0..10 {
+            let mapping = mapping(vec![
+                ("hello", "bookworm", "0:2.10-3", entry(&["amd64"])),
+                ("hello", "bookworm", "2.10-3", entry(&["arm64"])),
+            ]);
+            let madison = madison_for(&mapping, &["hello"], &MadisonFilter::default());
+            assert_eq!(
+                rows(&madison, "hello"),
+                [
+                    ("0:2.10-3", "bookworm", "amd64"),
+                    ("2.10-3", "bookworm", "arm64")
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn columns_reorder_header_and_rows() {
+        let mapping = mapping(vec![(
+            "hello",
+            "bookworm",
+            "2.10-3",
+            entry(&["source", "amd64"]),
+        )]);
+        let columns = Column::parse_list("codename, package,version").expect("valid columns");
+        let structure = || madison_for(&mapping, &["hello"], &MadisonFilter::default());
+        assert_eq!(
+            do_madison_csv(&mut structure(), names(&["hello"]), &columns),
+            "codename,package,version\nbookworm,hello,2.10-3\n"
+        );
+        assert_eq!(
+            do_madison(&mut structure(), names(&["hello"]), &columns),
+            "bookworm | hello | 2.10-3\n"
+        );
+        assert!(Column::parse_list("codename,suite").is_err());
+    }
+
+    #[test]
+    fn rmadison_lines_match_dak_ls() {
+        let mapping = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["source", "amd64"])),
+            ("hello", "sid", "2.10-3+b1", entry(&["amd64"])),
+            ("zsh", "bookworm", "5.9-4", entry(&["amd64", "arm64"])),
+        ]);
+        let packages = names(&["hello", "zsh"]);
+        let mut structure = generate_madison_structure(
+            &mapping,
+            &packages,
+            &MadisonFilter::default(),
+            &version::Debian,
+        );
+        let body = do_madison_rmadison(&mut structure, packages);
+        assert_eq!(
+            body,
+            concat!(
+                " hello | 2.10-3    | bookworm | source, amd64\n",
+                " hello | 2.10-3+b1 | sid      | amd64\n",
+                " zsh   | 5.9-4     | bookworm | amd64, arm64\n",
+            )
+        );
+        // Every line is one rmadison can split into package, version, suite and architectures
+        let line = regex::Regex::new(r"^ \S+ +\| \S+ +\| \S+ +\| \S.*$").expect("valid regex");
+        assert!(body.lines().all(|l| line.is_match(l)), "{:?}", body);
+    }
+
+    #[test]
+    fn json_is_an_array_of_the_selected_columns() {
+        let mapping = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["source", "amd64"])),
+            ("zsh", "bookworm", "5.9-4", entry(&["amd64"])),
+        ]);
+        let structure = |packages: &[String]| {
+            generate_madison_structure(
+                &mapping,
+                packages,
+                &MadisonFilter::default(),
+                &version::Debian,
+            )
+        };
+        let packages = names(&["zsh", "hello"]);
+        let json = do_madison_json(&mut structure(&packages), packages, Column::DEFAULT);
+        let records: Vec<serde_json::Value> = serde_json::from_str(&json).expect("valid JSON");
+        assert_eq!(
+            records,
+            [
+                serde_json::json!({
+                    "package": "zsh",
+                    "version": "5.9-4",
+                    "codename": "bookworm",
+                    "architectures": "amd64",
+                }),
+                serde_json::json!({
+                    "package": "hello",
+                    "version": "2.10-3",
+                    "codename": "bookworm",
+                    "architectures": "source, amd64",
+                }),
+            ]
+        );
+        let columns = Column::parse_list("version,codename").expect("valid columns");
+        let packages = names(&["hello"]);
+        assert_eq!(
+            do_madison_json(&mut structure(&packages), packages, &columns),
+            r#"[{"version":"2.10-3","codename":"bookworm"}]"#
+        );
+        let packages = names(&["missing"]);
+        assert_eq!(
+            do_madison_json(&mut structure(&packages), packages, Column::DEFAULT),
+            "[]"
+        );
+    }
+
+    #[test]
+    fn only_json_lacks_a_trailing_newline() {
+        let mapping = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("hello", "sid", "2.10-5", entry(&["amd64"])),
+        ]);
+        for packages in [names(&["hello"]), names(&["missing"])] {
+            for format in [
+                OutputFormat::Table,
+                OutputFormat::Env,
+                OutputFormat::Summary,
+                OutputFormat::Yaml,
+                OutputFormat::Json,
+                OutputFormat::Csv,
+                OutputFormat::Rmadison,
+            ] {
+                let mut madison = generate_madison_structure(
+                    &mapping,
+                    &packages,
+                    &MadisonFilter::default(),
+                    &version::Debian,
+                );
+                let output =
+                    render_madison(format, &mut madison, packages.clone(), Column::DEFAULT);
+                let expected = if format == OutputFormat::Json {
+                    b']'
+                } else {
+                    b'\n'
+                };
+                assert_eq!(output.as_bytes().last(), Some(&expected), "{:?}", format);
+                assert!(!output.ends_with("\n\n"), "{:?}: {:?}", format, output);
+            }
+        }
+    }
+
+    #[test]
+    fn yaml_parses_back_into_the_records() {
+        let mapping = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["source", "amd64"])),
+            ("hello", "sid", "2.10-5", entry(&["amd64", "arm64"])),
+            ("zsh", "bookworm", "5.9-4", entry(&["amd64"])),
+        ]);
+        let packages = names(&["zsh", "hello", "missing"]);
+        let structure = || {
+            generate_madison_structure(
+                &mapping,
+                &packages,
+                &MadisonFilter::default(),
+                &version::Debian,
+            )
+        };
+        let yaml = do_madison_yaml(&mut structure(), packages.clone(), Column::ALL);
+        let parsed: serde_yaml::Mapping = serde_yaml::from_str(&yaml).expect("valid YAML");
+        let keys: Vec<_> = parsed.keys().filter_map(|key| key.as_str()).collect();
+        assert_eq!(keys, ["zsh", "hello"]);
+        let records: MadisonStructure = serde_yaml::from_str(&yaml).expect("records");
+        assert_eq!(records, structure());
+    }
+
+    #[test]
+    fn differs_from_only_reports_divergent_suites() {
+        let mapping = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("hello", "trixie", "2.10-3", entry(&["amd64"])),
+            ("hello", "sid", "2.10-5", entry(&["amd64"])),
+            ("zsh", "bookworm", "5.9-4", entry(&["amd64"])),
+            ("zsh", "sid", "5.9-4", entry(&["amd64"])),
+            // Not in the reference suite at all
+            ("newpkg", "sid", "1.0-1", entry(&["amd64"])),
+        ]);
+        let diverging = diverging_from(&mapping, "bookworm", &version::Debian);
+        assert_eq!(diverging.len(), 1);
+        let mut hello: Vec<_> = diverging["hello"].keys().collect();
+        hello.sort();
+        assert_eq!(hello, [&("sid".to_string(), "2.10-5".to_string())]);
+    }
+
+    #[test]
+    fn newer_than_finds_the_next_version_up() {
+        let mapping = mapping(vec![
+            ("foo", "bullseye", "1.0", entry(&["amd64"])),
+            ("foo", "bookworm", "1.5", entry(&["amd64"])),
+            ("foo", "bookworm-backports", "1.5", entry(&["amd64"])),
+            ("foo", "sid", "2.0", entry(&["amd64"])),
+        ]);
+        assert_eq!(
+            closest_newer_version(&mapping, "foo", "1.2", &version::Debian),
+            Some((
+                "1.5".to_string(),
+                names(&["bookworm", "bookworm-backports"])
+            ))
+        );
+        assert_eq!(
+            closest_newer_version(&mapping, "foo", "2.0", &version::Debian),
+            None
+        );
+    }
+
+    #[test]
+    fn env_names_are_identifiers_without_collisions() {
+        let mapping = mapping(vec![
+            ("foo-bar", "bookworm-security", "1.0-1", entry(&["amd64"])),
+            ("foo.bar", "bookworm-security", "1.0-2", entry(&["amd64"])),
+            ("libc6", "sid", "2.37-1", entry(&["amd64"])),
+            ("libc6", "sid", "2.37-2", entry(&["arm64"])),
+        ]);
+        let packages = names(&["foo-bar", "foo.bar", "libc6"]);
+        let mut madison = generate_madison_structure(
+            &mapping,
+            &packages,
+            &MadisonFilter::default(),
+            &version::Debian,
+        );
+        assert_eq!(
+            do_madison_env(&mut madison, packages),
+            "MADISON_FOO_BAR_BOOKWORM_SECURITY='1.0-1'\n\
+             MADISON_FOO_BAR_BOOKWORM_SECURITY_2='1.0-2'\n\
+             MADISON_LIBC6_SID='2.37-1'\n\
+             MADISON_LIBC6_SID_2='2.37-2'\n"
+        );
+    }
+
+    #[test]
+    fn origin_suite_follows_suite_order() {
+        let mapping = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("hello", "trixie", "2.10-5", entry(&["amd64"])),
+            ("hello", "sid", "2.10-5", entry(&["amd64"])),
+        ]);
+        let origin = |suite_order: &[&str]| {
+            let mut madison = madison_for(&mapping, &["hello"], &MadisonFilter::default());
+            reduce_to_origin_suite(&mut madison, &names(suite_order), &version::Debian);
+            madison.remove("hello").expect("hello has records")
+        };
+        let records = origin(&["sid", "trixie", "bookworm"]);
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            (records[0].version.as_str(), records[0].codename.as_str()),
+            ("2.10-5", "sid")
+        );
+        assert_eq!(origin(&["trixie", "sid"])[0].codename, "trixie");
+    }
+
+    #[test]
+    fn watched_packages_get_a_placeholder_row() {
+        let mapping = mapping(vec![("hello", "bookworm", "2.10-3", entry(&["amd64"]))]);
+        let filter = MadisonFilter {
+            watched_packages: HashSet::from(["hello".to_string(), "missing".to_string()]),
+            ..MadisonFilter::with_suite(Some("sid".to_string()))
+        };
+        let madison = madison_for(&mapping, &["hello", "missing", "unwatched"], &filter);
+        // Filtered out of every row counts as not found, too
+        assert_eq!(rows(&madison, "hello"), [("not found", "", "")]);
+        assert_eq!(rows(&madison, "missing"), [("not found", "", "")]);
+        assert!(!madison.contains_key("unwatched"));
+        assert_eq!(count_records(&madison), (0, 0));
+    }
+
+    #[test]
+    fn diff_mappings_reports_changes() {
+        let old = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("hello", "sid", "2.10-3", entry(&["amd64"])),
+            ("gone", "sid", "1.0-1", entry(&["amd64"])),
+            ("same", "sid", "3.0-1", entry(&["amd64"])),
+        ]);
+        let new = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("hello", "sid", "2.10-5", entry(&["amd64"])),
+            ("hello", "trixie", "2.10-5", entry(&["amd64"])),
+            ("added", "sid", "0.1-1", entry(&["amd64"])),
+            ("same", "sid", "3.0-1", entry(&["amd64"])),
+        ]);
+        let change =
+            |suite: &str, old_version: Option<&str>, new_version: Option<&str>| VersionChange {
+                package: "hello".to_string(),
+                suite: suite.to_string(),
+                old_version: old_version.map(str::to_string),
+                new_version: new_version.map(str::to_string),
+            };
+        assert_eq!(
+            diff_mappings(&old, &new, &version::Debian),
+            MappingDiff {
+                added: names(&["added"]),
+                removed: names(&["gone"]),
+                changed: vec![
+                    change("sid", Some("2.10-3"), Some("2.10-5")),
+                    change("trixie", None, Some("2.10-5")),
+                ],
+            }
+        );
+        assert_eq!(
+            diff_mappings(&new, &new, &version::Debian),
+            MappingDiff::default()
+        );
+    }
+
+    #[test]
+    fn summary_puts_each_package_on_one_line() {
+        let mapping = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("hello", "sid", "2.10-5", entry(&["amd64"])),
+            ("zsh", "sid", "5.9-6", entry(&["amd64"])),
+        ]);
+        let packages = names(&["zsh", "hello", "missing"]);
+        let mut madison = generate_madison_structure(
+            &mapping,
+            &packages,
+            &MadisonFilter::default(),
+            &version::Debian,
+        );
+        assert_eq!(
+            do_madison_summary(&mut madison, packages),
+            "zsh: sid=5.9-6\nhello: bookworm=2.10-3 sid=2.10-5\n"
+        );
+    }
+
+    #[test]
+    fn eol_suites_are_marked() {
+        let mapping = mapping(vec![
+            ("hello", "buster", "2.10-2", entry(&["amd64"])),
+            ("hello", "buster/main", "2.10-2", entry(&["amd64"])),
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+        ]);
+        let mut madison = madison_for(&mapping, &["hello"], &MadisonFilter::default());
+        mark_eol_suites(&mut madison, &names(&["buster"]));
+        assert_eq!(
+            rows(&madison, "hello"),
+            [
+                ("2.10-2", "buster (EOL)", "amd64"),
+                ("2.10-2", "buster/main (EOL)", "amd64"),
+                ("2.10-3", "bookworm", "amd64"),
+            ]
+        );
     }
 }
@@ -1,81 +1,149 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
-    time::Duration,
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime},
 };
 
+use fapt::{sources_list, system::System};
 use log::info;
-use rocket::{Build, Rocket};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rocket::{
+    data::{Data, ToByteUnit},
+    fairing::AdHoc,
+    http::{ContentType, Status},
+    response::stream::TextStream,
+    Build, Rocket,
+};
 use rocket_dyn_templates::{context, Template};
 use rocket_prometheus::{
-    prometheus::{opts, IntCounter, IntCounterVec},
+    prometheus::{opts, IntGaugeVec, Registry, TextEncoder},
     PrometheusMetrics,
 };
+use serde::Serialize;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
 use tokio::time::sleep;
 
+use crate::metrics::MadisonMetrics;
 use crate::{
-    build_madison_mapping, do_madison, generate_madison_structure, init_system, key_func,
-    MadisonConfig, MadisonMapping,
+    build_madison_mapping, build_madison_mapping_of, changed_keys, do_madison, do_madison_rmadison,
+    expired_suites, fallback_count, generate_madison_structure, init_system,
+    init_system_with_fallback, key_func, mark_eol_suites, matching_packages, release_fingerprints,
+    replace_keys, update_system, version::VersionComparator, Column, ListingCache, MadisonConfig,
+    MadisonFilter, MadisonMapping, MadisonOutputRecord, MadisonStructure, RegexLimits,
 };
 
+mod admin;
+mod conditional;
+mod history;
 mod templates;
 
-#[derive(Clone)]
-struct MadisonMetrics {
-    update_attempts: IntCounter,
-    update_failures: IntCounter,
-    package_lookups: IntCounterVec,
-    mapping_rebuilds: IntCounter,
-}
-
-impl MadisonMetrics {
-    fn new() -> Result<Self, anyhow::Error> {
-        Ok(Self {
-            update_attempts: IntCounter::new(
-                "madison_rs_apt_update_attempts",
-                "Count of system apt update attempts",
-            )?,
-            update_failures: IntCounter::new(
-                "madison_rs_apt_update_failures",
-                "Count of failed system apt update attempts",
-            )?,
-            package_lookups: IntCounterVec::new(
-                opts!(
-                    "madison_rs_package_lookups",
-                    "Count of packages looked up, and how"
-                ),
-                &["route", "package_name"],
-            )?,
-            mapping_rebuilds: IntCounter::new(
-                "madison_rs_mapping_rebuilds",
-                "Count of rebuilds of the internal madison mapping",
-            )?,
-        })
-    }
+use admin::{Admin, AdminToken};
+use conditional::{Conditional, IfModifiedSince};
+use history::History;
 
-    fn register_with(self, prometheus: &PrometheusMetrics) -> Result<(), anyhow::Error> {
-        let registry = prometheus.registry();
-        registry.register(Box::new(self.update_attempts))?;
-        registry.register(Box::new(self.update_failures))?;
-        registry.register(Box::new(self.package_lookups))?;
-        registry.register(Box::new(self.mapping_rebuilds))?;
-        Ok(())
-    }
+/// What the update loop needs to rebuild after checking for updates.
+#[derive(Debug, PartialEq, Eq)]
+enum Rebuild {
+    Nothing,
+    Full,
+    /// Only the rows under these keys of the default mapping, and the rows of the grouped
+    /// mappings under keys whose Release files changed
+    Keys(HashSet<String>),
 }
 
+const DEFAULT_MAX_PACKAGE_METRICS: usize = 10_000;
+const DEFAULT_MAX_REQUEST_BODY_BYTES: u64 = 64 * 1024;
+const DEFAULT_UPDATE_INTERVAL_SECS: u64 = 60;
+const DEFAULT_HISTORY_RETENTION_DAYS: u64 = 7;
+const DEFAULT_PER_PAGE: usize = 100;
+const MAX_PER_PAGE: usize = 1_000;
+const DEFAULT_MAX_REGEX_LENGTH: usize = 256;
+const DEFAULT_MAX_REGEX_MATCHES: usize = 1_000;
+
 struct MadisonState {
     madison_mapping: Arc<RwLock<MadisonMapping>>,
+    /// Mappings for each of the configured `groupings`, keyed by grouping name
+    grouped_mappings: Arc<RwLock<HashMap<String, MadisonMapping>>>,
+    /// When `madison_mapping` was last successfully (re)built
+    last_rebuild: Arc<RwLock<Option<SystemTime>>>,
+    /// Whether the most recent update attempt failed, so we may be serving outdated data
+    stale: Arc<AtomicBool>,
+    /// Suites whose Release file was past its `Valid-Until` at the last (re)build
+    expired_suites: Arc<RwLock<Vec<String>>>,
+    eol_suites: Vec<String>,
+    suite_aliases: HashMap<String, String>,
+    watched_packages: HashSet<String>,
+    /// The architectures this instance tracks
+    arches: Vec<String>,
+    max_package_metrics: usize,
+    max_request_body_bytes: u64,
+    instance_name: Option<String>,
+    /// As configured at startup
+    comparator: &'static dyn VersionComparator,
+    regex_limits: RegexLimits,
+    strip_arch_qualifiers: bool,
+    /// The configuration loaded at startup (which is never reloaded), with secrets redacted
+    redacted_config: serde_json::Value,
+}
+
+impl MadisonState {
+    /// The filter for a request restricted to `suite`, showing only `arches` (both of which may
+    /// be comma-separated lists).
+    fn filter(&self, suite: Option<String>, arches: Option<String>) -> MadisonFilter {
+        let mut filter = MadisonFilter {
+            watched_packages: self.watched_packages.clone(),
+            suite_aliases: self.suite_aliases.clone(),
+            ..MadisonFilter::with_suite(suite)
+        };
+        if let Some(arches) = arches {
+            filter.add_arches(&arches);
+        }
+        filter
+    }
+
+    /// `packages` without any architecture qualifiers (if `strip_arch_qualifiers` is enabled),
+    /// whose architectures are added to `filter`.
+    fn normalize(&self, packages: Vec<String>, filter: &mut MadisonFilter) -> Vec<String> {
+        if self.strip_arch_qualifiers {
+            filter.take_arch_qualifiers(packages)
+        } else {
+            packages
+        }
+    }
 }
 
+/// The search form.  Requests to `/` without a `package` (e.g. `/?s=jammy`) are forwarded here by
+/// the query routes below, so this is also what they show.
 #[get("/")]
-async fn index() -> Template {
-    let context: HashMap<String, String> = HashMap::new();
-    Template::render("index.html", &context)
+async fn index(state: &rocket::State<MadisonState>) -> Template {
+    index_template(state)
+}
+
+fn index_template(state: &MadisonState) -> Template {
+    Template::render("index.html", context! {instance_name: &state.instance_name})
+}
+
+/// The stylesheet for the HTML pages; a `style.css` template in `template_dir` overrides it.
+#[get("/style.css")]
+async fn style() -> (ContentType, Template) {
+    (ContentType::CSS, Template::render("style.css", context! {}))
 }
 
+/// Split the (already percent-decoded) `package` query value into package names.
+///
+/// Names are separated by whitespace, and contain none, so nothing else is altered.  Note that in
+/// a query string `+` decodes to a space, so clients must send a literal `+` (as in `libfoo++`)
+/// percent-encoded, as `%2B`.
 fn get_packages(package_str: String, metrics: &MadisonMetrics, source: &str) -> Vec<String> {
     package_str
-        .split(" ")
+        .split_whitespace()
         .map(|s| {
             metrics
                 .package_lookups
@@ -86,84 +154,945 @@ fn get_packages(package_str: String, metrics: &MadisonMetrics, source: &str) ->
         .collect()
 }
 
-#[get("/?<package>&text=on&<s>")]
+/// rmadison's query; with `r` (as sent by `rmadison -r`), each package is a regex to match
+/// package names against, within the configured `max_regex_length` and `max_regex_matches`.
+#[get("/?<package>&text=on&<s>&<a>&<r>&<by>")]
+#[allow(clippy::too_many_arguments)]
 async fn madison(
     package: String,
     s: Option<String>,
+    a: Option<String>,
+    r: Option<String>,
+    by: Option<String>,
+    if_modified_since: IfModifiedSince,
+    state: &rocket::State<MadisonState>,
+    metrics: &rocket::State<MadisonMetrics>,
+) -> Result<Option<Conditional<String>>, (Status, String)> {
+    madison_text(
+        RmadisonQuery {
+            package,
+            s,
+            a,
+            r,
+            by,
+        },
+        if_modified_since,
+        state,
+        metrics,
+        "rmadison",
+        &|madison, packages| {
+            mark_eol_suites(madison, &state.eol_suites);
+            do_madison(madison, packages, Column::DEFAULT)
+        },
+    )
+}
+
+/// The same query as `/`, answered as ftp-master's `dak ls`-backed `/madison` API does, for use
+/// as an rmadison backend (`rmadison -u http://host/madison`): each record is a line like
+/// ` package | version | suite | architectures` (padded so the columns align), with no markers
+/// like `(EOL)`, and the body is empty when nothing matches.
+#[get("/madison?<package>&text=on&<s>&<a>&<r>")]
+#[allow(clippy::too_many_arguments)]
+async fn madison_backend(
+    package: String,
+    s: Option<String>,
+    a: Option<String>,
+    r: Option<String>,
+    if_modified_since: IfModifiedSince,
+    state: &rocket::State<MadisonState>,
+    metrics: &rocket::State<MadisonMetrics>,
+) -> Result<Option<Conditional<String>>, (Status, String)> {
+    madison_text(
+        RmadisonQuery {
+            package,
+            s,
+            a,
+            r,
+            by: None,
+        },
+        if_modified_since,
+        state,
+        metrics,
+        "madison",
+        &do_madison_rmadison,
+    )
+}
+
+/// The parameters of an rmadison query.
+struct RmadisonQuery {
+    package: String,
+    s: Option<String>,
+    a: Option<String>,
+    r: Option<String>,
+    by: Option<String>,
+}
+
+/// Answer `query`, rendering its records with `render`.
+fn madison_text(
+    query: RmadisonQuery,
+    if_modified_since: IfModifiedSince,
+    state: &MadisonState,
+    metrics: &MadisonMetrics,
+    route: &str,
+    render: &dyn Fn(&mut MadisonStructure, Vec<String>) -> String,
+) -> Result<Option<Conditional<String>>, (Status, String)> {
+    let RmadisonQuery {
+        package,
+        s,
+        a,
+        r,
+        by,
+    } = query;
+    let mut packages = get_packages(package, metrics, route);
+    if packages.is_empty() {
+        // rmadison itself never sends this, so it's most likely a hand-written query
+        return Err((Status::BadRequest, "no package name given\n".to_string()));
+    }
+    let last_rebuild = *state.last_rebuild.read().expect("read access failed");
+    let ro_mapping = state.madison_mapping.read().expect("read access failed");
+    let ro_grouped = state.grouped_mappings.read().expect("read access failed");
+    let mapping = match &by {
+        Some(by) => match ro_grouped.get(by) {
+            Some(mapping) => mapping,
+            None => return Ok(None),
+        },
+        None => &*ro_mapping,
+    };
+    let mut filter = state.filter(s, a);
+    if r.is_some() {
+        // Matches are counted (and limited) before any output is generated
+        let mut matched = vec![];
+        for pattern in &packages {
+            for package in matching_packages(mapping, pattern, state.regex_limits)
+                .map_err(|e| (Status::BadRequest, format!("{}\n", e)))?
+            {
+                if !matched.contains(&package) {
+                    matched.push(package);
+                }
+            }
+        }
+        packages = matched;
+    } else {
+        packages = state.normalize(packages, &mut filter);
+    }
+    let mut madison = generate_madison_structure(mapping, &packages, &filter, state.comparator);
+    Ok(Some(Conditional::new(
+        last_rebuild,
+        &if_modified_since,
+        render(&mut madison, packages),
+    )))
+}
+
+/// Look up many packages at once, given as whitespace-separated names in the request body.
+#[post("/lookup?<s>", data = "<body>")]
+async fn lookup(
+    body: Data<'_>,
+    s: Option<String>,
     state: &rocket::State<MadisonState>,
     metrics: &rocket::State<MadisonMetrics>,
-) -> Result<String, rocket::response::Debug<anyhow::Error>> {
+) -> Result<String, Status> {
+    let body = body
+        .open(state.max_request_body_bytes.bytes())
+        .into_string()
+        .await
+        .map_err(|_| Status::BadRequest)?;
+    if !body.is_complete() {
+        return Err(Status::PayloadTooLarge);
+    }
+    let mut filter = state.filter(s, None);
+    let packages = state.normalize(
+        get_packages(body.into_inner(), metrics, "lookup"),
+        &mut filter,
+    );
     let ro_mapping = state.madison_mapping.read().expect("read access failed");
-    let packages = get_packages(package, metrics, "rmadison");
-    let mut madison = generate_madison_structure(&ro_mapping, &packages, s);
-    Ok(do_madison(&mut madison, packages))
+    let mut madison = generate_madison_structure(&ro_mapping, &packages, &filter, state.comparator);
+    mark_eol_suites(&mut madison, &state.eol_suites);
+    Ok(do_madison(&mut madison, packages, Column::DEFAULT))
 }
 
-#[get("/?<package>&<s>")]
+/// The HTML results page, showing `per_page` records (in query order) at a time.
+#[get("/?<package>&<s>&<a>&<by>&<page>&<per_page>")]
+#[allow(clippy::too_many_arguments)]
 async fn madison_html(
     package: String,
     s: Option<String>,
+    a: Option<String>,
+    by: Option<String>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    if_modified_since: IfModifiedSince,
+    state: &rocket::State<MadisonState>,
+    metrics: &rocket::State<MadisonMetrics>,
+) -> Option<Conditional<Template>> {
+    let last_rebuild = *state.last_rebuild.read().expect("read access failed");
+    let ro_mapping = state.madison_mapping.read().expect("read access failed");
+    let ro_grouped = state.grouped_mappings.read().expect("read access failed");
+    let mapping = match &by {
+        Some(by) => ro_grouped.get(by)?,
+        None => &*ro_mapping,
+    };
+    let query = context! {package: &package, s: &s, a: &a, by: &by};
+    let packages = get_packages(package.clone(), metrics, "html");
+    if packages.is_empty() {
+        // e.g. the search form submitted with only whitespace
+        return Some(Conditional::new(
+            last_rebuild,
+            &if_modified_since,
+            index_template(state),
+        ));
+    }
+    let mut filter = state.filter(s.clone(), a.clone());
+    let packages = state.normalize(packages, &mut filter);
+    let mut madison = generate_madison_structure(mapping, &packages, &filter, state.comparator);
+    mark_eol_suites(&mut madison, &state.eol_suites);
+    let page = page.unwrap_or(1).max(1);
+    let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+    let (madison, total) = paginate(madison, &packages, page, per_page);
+    Some(Conditional::new(
+        last_rebuild,
+        &if_modified_since,
+        Template::render(
+            "package.html",
+            context! {
+                madison: madison,
+                stale: state.stale.load(Ordering::Relaxed),
+                instance_name: &state.instance_name,
+                query: query,
+                page: page,
+                per_page: per_page,
+                total: total,
+                prev_page: if page > 1 { Some(page - 1) } else { None },
+                next_page: if page * per_page < total { Some(page + 1) } else { None },
+            },
+        ),
+    ))
+}
+
+/// A permalink for one package's HTML results, in every suite; 404s if it isn't tracked.
+#[get("/package/<name>")]
+async fn package_page(
+    name: String,
+    if_modified_since: IfModifiedSince,
     state: &rocket::State<MadisonState>,
     metrics: &rocket::State<MadisonMetrics>,
-) -> Template {
+) -> Option<Conditional<Template>> {
+    let last_rebuild = *state.last_rebuild.read().expect("read access failed");
     let ro_mapping = state.madison_mapping.read().expect("read access failed");
-    let packages = get_packages(package, metrics, "html");
-    Template::render(
-        "package.html",
-        context! {madison: generate_madison_structure(&ro_mapping, &packages, s)},
+    let mut filter = state.filter(None, None);
+    let packages = state.normalize(get_packages(name.clone(), metrics, "package"), &mut filter);
+    let mut madison = generate_madison_structure(&ro_mapping, &packages, &filter, state.comparator);
+    if madison.values().all(Vec::is_empty) {
+        return None;
+    }
+    mark_eol_suites(&mut madison, &state.eol_suites);
+    let madison: PackageRecords = packages
+        .iter()
+        .filter_map(|package| madison.remove_entry(package))
+        .collect();
+    Some(Conditional::new(
+        last_rebuild,
+        &if_modified_since,
+        Template::render(
+            "package.html",
+            context! {
+                madison: madison,
+                stale: state.stale.load(Ordering::Relaxed),
+                instance_name: &state.instance_name,
+                query: context! {package: &name},
+            },
+        ),
+    ))
+}
+
+/// Records grouped by package, in the order the packages were asked for (which templates show
+/// them in, unlike a `MadisonStructure`'s).
+type PackageRecords = Vec<(String, Vec<MadisonOutputRecord>)>;
+
+/// The records of page `page` (from 1) of `madison`, taking `packages` in order, and the total
+/// number of records.
+fn paginate(
+    mut madison: MadisonStructure,
+    packages: &[String],
+    page: usize,
+    per_page: usize,
+) -> (PackageRecords, usize) {
+    let records: Vec<_> = packages
+        .iter()
+        .filter_map(|package| madison.remove(package))
+        .flatten()
+        .collect();
+    let total = records.len();
+    let mut page_records: PackageRecords = vec![];
+    for record in records
+        .into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+    {
+        match page_records.last_mut() {
+            Some((package, package_records)) if *package == record.package => {
+                package_records.push(record)
+            }
+            _ => page_records.push((record.package.clone(), vec![record])),
+        }
+    }
+    (page_records, total)
+}
+
+/// Expose each tracked package version as a gauge, for dashboards rather than alerting.
+///
+/// Every (package, suite, version) combination is a separate series, so the number of samples is
+/// capped at `max_package_metrics` (taking packages in name order).
+#[get("/metrics/packages")]
+async fn package_metrics(
+    state: &rocket::State<MadisonState>,
+) -> Result<(ContentType, String), rocket::response::Debug<anyhow::Error>> {
+    let package_versions = IntGaugeVec::new(
+        opts!(
+            "madison_rs_package_version",
+            "Tracked package versions, by suite (always 1)"
+        ),
+        &["package", "suite", "version"],
     )
+    .map_err(anyhow::Error::from)?;
+    {
+        let ro_mapping = state.madison_mapping.read().expect("read access failed");
+        let mut packages: Vec<_> = ro_mapping.keys().collect();
+        packages.sort();
+        let samples = packages
+            .into_iter()
+            .flat_map(|package| {
+                ro_mapping[package]
+                    .keys()
+                    .map(move |(codename, version)| (package, codename, version))
+            })
+            .take(state.max_package_metrics);
+        for (package, codename, version) in samples {
+            package_versions
+                .with_label_values(&[package, codename, version])
+                .set(1);
+        }
+    }
+    let registry = Registry::new();
+    registry
+        .register(Box::new(package_versions))
+        .map_err(anyhow::Error::from)?;
+    let body = TextEncoder::new()
+        .encode_to_string(&registry.gather())
+        .map_err(anyhow::Error::from)?;
+    Ok((ContentType::Plain, body))
 }
 
-pub async fn rocket(key_func: &'static key_func::KeyFunc) -> Rocket<Build> {
-    let rocket = rocket::build();
-    let figment = rocket.figment();
-    let config: MadisonConfig = figment.extract().expect("config");
-    let metrics = MadisonMetrics::new().unwrap();
+/// The codenames (as a sorted JSON array) that have any version of `package`, or a 404 if it isn't
+/// tracked at all.
+#[get("/suites-for?<package>&<s>")]
+async fn suites_for(
+    package: String,
+    s: Option<String>,
+    state: &rocket::State<MadisonState>,
+) -> Option<(ContentType, String)> {
+    let filter = state.filter(s, None);
+    let ro_mapping = state.madison_mapping.read().expect("read access failed");
+    let mut codenames: Vec<_> = ro_mapping
+        .get(package.trim())?
+        .iter()
+        .filter(|((codename, version), entry)| {
+            filter.matches(codename, version, entry, state.comparator)
+        })
+        .map(|((codename, _), _)| codename.as_str())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    codenames.sort();
+    Some((
+        ContentType::JSON,
+        serde_json::to_string(&codenames).expect("serialize codenames"),
+    ))
+}
 
-    let system = init_system(&config).await.expect("fapt System init");
+#[get("/arches")]
+async fn arches(state: &rocket::State<MadisonState>) -> (ContentType, String) {
+    (
+        ContentType::JSON,
+        serde_json::to_string(&state.arches).expect("serialize arches"),
+    )
+}
 
-    let mapping_lock = Arc::new(RwLock::new(HashMap::new()));
-    let c_lock = mapping_lock.clone();
-    let task_metrics = metrics.clone();
-    tokio::task::spawn(async move {
-        {
-            // Take the lock immediately for initialisation
-            let mut madison_mapping = c_lock.write().expect("write access failed");
-            info!("Initialising madison mapping");
-            *madison_mapping = build_madison_mapping(&system, key_func, config.include_source_arch)
-                .expect("build_madison_mapping");
+#[derive(Serialize)]
+struct Stats {
+    /// Seconds since the Unix epoch
+    last_rebuild: Option<u64>,
+    stale: bool,
+    expired_suites: Vec<String>,
+    package_count: usize,
+}
+
+/// Liveness: the server is up and answering requests.
+#[get("/healthz")]
+async fn healthz() -> &'static str {
+    "ok\n"
+}
+
+/// Readiness: 200 once the initial mapping has been built with some packages in it, 503 before.
+#[get("/readyz")]
+async fn readyz(state: &rocket::State<MadisonState>) -> (Status, &'static str) {
+    // The mapping is write-locked throughout the initial build, which is over by the time
+    // `last_rebuild` is set; after that, it is only write-locked to swap rebuilt rows in
+    let initialised = state
+        .last_rebuild
+        .read()
+        .expect("read access failed")
+        .is_some();
+    let ready = initialised
+        && match state.madison_mapping.try_read() {
+            Ok(mapping) => !mapping.is_empty(),
+            Err(_) => true,
+        };
+    if ready {
+        (Status::Ok, "ready\n")
+    } else {
+        (Status::ServiceUnavailable, "not ready\n")
+    }
+}
+
+/// Summary information about the state of the mapping, as JSON.
+#[get("/stats")]
+async fn stats(state: &rocket::State<MadisonState>) -> (ContentType, String) {
+    let stats = Stats {
+        last_rebuild: state
+            .last_rebuild
+            .read()
+            .expect("read access failed")
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs()),
+        stale: state.stale.load(Ordering::Relaxed),
+        expired_suites: state
+            .expired_suites
+            .read()
+            .expect("read access failed")
+            .clone(),
+        package_count: state
+            .madison_mapping
+            .read()
+            .expect("read access failed")
+            .len(),
+    };
+    (
+        ContentType::JSON,
+        serde_json::to_string(&stats).expect("serialize stats"),
+    )
+}
+
+#[derive(Serialize)]
+struct ExportLine<'a> {
+    package: &'a str,
+    records: &'a [MadisonOutputRecord],
+}
+
+/// When each of `package`'s versions was first seen in each suite (or just in the comma-separated
+/// suites `s`), oldest first, as JSON.
+#[get("/history?<package>&<s>")]
+async fn version_history(
+    package: String,
+    s: Option<String>,
+    history: &rocket::State<Arc<RwLock<History>>>,
+) -> (ContentType, String) {
+    let suites: Option<Vec<_>> = s.map(|s| {
+        s.split(',')
+            .map(str::trim)
+            .filter(|suite| !suite.is_empty())
+            .map(str::to_string)
+            .collect()
+    });
+    let history = history.read().expect("read access failed");
+    let observations = history.query(package.trim(), suites.as_deref());
+    (
+        ContentType::JSON,
+        serde_json::to_string(&observations).expect("serialize history"),
+    )
+}
+
+/// The configured `selftest_package` and `selftest_suite`, as managed state.
+struct Canary {
+    package: String,
+    suite: Option<String>,
+}
+
+/// A smoke test of the whole query path: 200 if the canary package is found (in its suite, if
+/// one is configured), otherwise 500, with the rows found or what was missing.
+#[get("/selftest")]
+async fn selftest(
+    state: &rocket::State<MadisonState>,
+    canary: &rocket::State<Canary>,
+) -> (Status, String) {
+    let ro_mapping = state.madison_mapping.read().expect("read access failed");
+    let packages = vec![canary.package.clone()];
+    let mut madison = generate_madison_structure(
+        &ro_mapping,
+        &packages,
+        &state.filter(canary.suite.clone(), None),
+        state.comparator,
+    );
+    let found = madison
+        .get(&canary.package)
+        .map(|records| records.iter().any(|record| !record.codename.is_empty()))
+        .unwrap_or(false);
+    if found {
+        (
+            Status::Ok,
+            do_madison(&mut madison, packages, Column::DEFAULT),
+        )
+    } else {
+        (
+            Status::InternalServerError,
+            format!(
+                "canary package {} not found{}\n",
+                canary.package,
+                canary
+                    .suite
+                    .as_ref()
+                    .map(|suite| format!(" in {}", suite))
+                    .unwrap_or_default()
+            ),
+        )
+    }
+}
+
+/// Record the versions in `mapping` in `history`, if one is kept.
+fn record_history(history: &Option<Arc<RwLock<History>>>, mapping: &MadisonMapping) {
+    if let Some(history) = history {
+        let mut history = history.write().expect("write access failed");
+        if let Err(e) = history.record(mapping, SystemTime::now()) {
+            warn!("Failed to record history: {}", e);
         }
+    }
+}
 
-        loop {
-            sleep(Duration::from_secs(60)).await;
-            info!("Checking for updates");
-            task_metrics.update_attempts.inc();
-            let did_update = match system.update().await {
-                Ok(val) => val,
-                Err(e) => {
-                    task_metrics.update_failures.inc();
-                    warn!("Encountered error when updating: {}", e);
-                    false
+/// Stream every tracked package, in every suite, as madison's text output.
+///
+/// Like `/export`, the mapping lock is only held while each package's rows are produced; columns
+/// are aligned within each package rather than across the whole report.
+#[get("/all")]
+async fn all_report(
+    state: &rocket::State<MadisonState>,
+) -> (ContentType, TextStream![String + '_]) {
+    let mut packages: Vec<_> = {
+        let ro_mapping = state.madison_mapping.read().expect("read access failed");
+        ro_mapping.keys().cloned().collect()
+    };
+    packages.sort();
+    let stream = TextStream! {
+        for package in packages {
+            let rows = {
+                let ro_mapping = state.madison_mapping.read().expect("read access failed");
+                let packages = vec![package];
+                let mut madison = generate_madison_structure(
+                    &ro_mapping,
+                    &packages,
+                    &state.filter(None, None),
+                    state.comparator,
+                );
+                mark_eol_suites(&mut madison, &state.eol_suites);
+                if madison.values().all(Vec::is_empty) {
+                    None
+                } else {
+                    Some(do_madison(&mut madison, packages, Column::DEFAULT))
                 }
             };
-            if did_update {
-                info!("Update happened: updating mapping");
-                let new_mapping =
-                    build_madison_mapping(&system, key_func, config.include_source_arch)
-                        .expect("build_madison_mapping");
-                let mut madison_mapping = c_lock.write().expect("write access failed");
-                *madison_mapping = new_mapping;
-                task_metrics.mapping_rebuilds.inc();
+            if let Some(rows) = rows {
+                yield rows;
+            }
+        }
+    };
+    (ContentType::Plain, stream)
+}
+
+/// Stream every tracked package as newline-delimited JSON, one package per line.
+///
+/// The mapping lock is only held while each line is produced, so a rebuild part-way through an
+/// export is picked up by the remaining lines.
+#[get("/export")]
+async fn export(state: &rocket::State<MadisonState>) -> (ContentType, TextStream![String + '_]) {
+    let mut packages: Vec<_> = {
+        let ro_mapping = state.madison_mapping.read().expect("read access failed");
+        ro_mapping.keys().cloned().collect()
+    };
+    packages.sort();
+    let stream = TextStream! {
+        for package in packages {
+            let line = {
+                let ro_mapping = state.madison_mapping.read().expect("read access failed");
+                let packages = vec![package];
+                generate_madison_structure(
+                    &ro_mapping,
+                    &packages,
+                    &MadisonFilter::default(),
+                    state.comparator,
+                )
+                    .iter()
+                    .map(|(package, records)| ExportLine { package, records })
+                    .map(|line| serde_json::to_string(&line).expect("serialize export line"))
+                    .next()
+            };
+            if let Some(line) = line {
+                yield line + "\n";
+            }
+        }
+    };
+    (ContentType::new("application", "x-ndjson"), stream)
+}
+
+/// The binaries of `suite` as a minimal apt `Packages` index, e.g. for `apt-cache` listings.
+#[get("/dists/<suite>/Packages?<arch>")]
+async fn packages_index(
+    suite: &str,
+    arch: Option<&str>,
+    state: &rocket::State<MadisonState>,
+) -> (ContentType, String) {
+    let ro_mapping = state.madison_mapping.read().expect("read access failed");
+    (
+        ContentType::Plain,
+        crate::packages_index(&ro_mapping, suite, arch, state.comparator),
+    )
+}
+
+#[derive(Serialize)]
+struct ValidatedSourcesEntry {
+    url: String,
+    suite: String,
+    components: Vec<String>,
+    arches: Option<String>,
+    source: bool,
+}
+
+/// Parse a candidate sources.list, reporting what it would track (or why it doesn't parse)
+/// without touching the running configuration.
+#[post("/validate-sources", data = "<body>")]
+async fn validate_sources(body: String) -> (Status, (ContentType, String)) {
+    let (status, response) = match sources_list::read(body.as_bytes()) {
+        Ok(entries) => (
+            Status::Ok,
+            serde_json::to_value(
+                entries
+                    .into_iter()
+                    .map(|entry| ValidatedSourcesEntry {
+                        url: entry.url,
+                        suite: entry.suite_codename,
+                        components: entry.components,
+                        arches: entry.arch,
+                        source: entry.src,
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+        ),
+        Err(e) => (
+            Status::BadRequest,
+            serde_json::to_value(HashMap::from([("error", e.to_string())])),
+        ),
+    };
+    (
+        status,
+        (
+            ContentType::JSON,
+            response.expect("serialize sources").to_string(),
+        ),
+    )
+}
+
+/// Parse and validate an address for madison-web to bind to.
+///
+/// IPv6 addresses may be given bare or bracketed (`::1` or `[::1]`).  Binding to `::` accepts
+/// IPv4 connections too unless the host has `net.ipv6.bindv6only` enabled.
+pub fn bind_address(address: &str) -> Result<IpAddr, anyhow::Error> {
+    let address = address.trim();
+    let unbracketed = address
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(address);
+    unbracketed
+        .parse()
+        .map_err(|e| anyhow::anyhow!("invalid bind address {:?}: {}", address, e))
+}
+
+/// Build a mapping for each of the configured `groupings`.
+fn build_grouped_mappings(
+    system: &System,
+    config: &MadisonConfig,
+) -> Result<HashMap<String, MadisonMapping>, anyhow::Error> {
+    config
+        .groupings
+        .iter()
+        .map(|name| {
+            let key_func = key_func::by_name(name)
+                .ok_or_else(|| anyhow::anyhow!("unknown grouping {:?}", name))?;
+            Ok((
+                name.clone(),
+                build_madison_mapping(system, key_func, config)?,
+            ))
+        })
+        .collect()
+}
+
+/// The Release fingerprints of each of the configured `groupings`, keyed as that grouping is.
+fn grouping_fingerprints(
+    system: &System,
+    config: &MadisonConfig,
+) -> Result<HashMap<String, HashMap<String, u64>>, anyhow::Error> {
+    config
+        .groupings
+        .iter()
+        .map(|name| {
+            let key_func = key_func::by_name(name)
+                .ok_or_else(|| anyhow::anyhow!("unknown grouping {:?}", name))?;
+            Ok((
+                name.clone(),
+                release_fingerprints(system, key_func, config)?,
+            ))
+        })
+        .collect()
+}
+
+/// Rebuild only the rows of the grouped mappings under keys whose fingerprint differs from
+/// `fingerprints`, which are updated to match.  The write lock is only taken to swap each
+/// grouping's rebuilt rows in.
+fn update_grouped_mappings(
+    system: &System,
+    config: &MadisonConfig,
+    grouped_mappings: &RwLock<HashMap<String, MadisonMapping>>,
+    fingerprints: &mut HashMap<String, HashMap<String, u64>>,
+) -> Result<(), anyhow::Error> {
+    for (name, new_fingerprints) in grouping_fingerprints(system, config)? {
+        let changed = match fingerprints.get(&name) {
+            Some(old_fingerprints) => changed_keys(old_fingerprints, &new_fingerprints),
+            None => new_fingerprints.keys().cloned().collect(),
+        };
+        if !changed.is_empty() {
+            let key_func = key_func::by_name(&name).expect("grouping_fingerprints checked it");
+            let partial = build_madison_mapping_of(system, key_func, config, Some(&changed), None)?;
+            let mut grouped_mappings = grouped_mappings.write().expect("write access failed");
+            replace_keys(
+                grouped_mappings.entry(name.clone()).or_default(),
+                partial,
+                &changed,
+            );
+        }
+        fingerprints.insert(name, new_fingerprints);
+    }
+    Ok(())
+}
+
+/// `config` as JSON, with secrets replaced by `"[redacted]"`.
+fn redacted_config(config: &MadisonConfig) -> serde_json::Value {
+    let mut value = serde_json::to_value(config).expect("serialize config");
+    if let Some(token) = value.get_mut("admin_token") {
+        if !token.is_null() {
+            *token = "[redacted]".into();
+        }
+    }
+    value
+}
+
+/// The effective configuration, for checking what a running instance actually loaded.
+#[get("/config")]
+async fn effective_config(
+    _admin: Admin,
+    state: &rocket::State<MadisonState>,
+) -> (ContentType, String) {
+    (ContentType::JSON, state.redacted_config.to_string())
+}
+
+/// How long to wait before the next periodic update: `interval`, plus a random delay of up to
+/// `max_jitter`.
+fn update_sleep(interval: Duration, max_jitter: Duration, rng: &mut impl Rng) -> Duration {
+    let max_jitter_ms = max_jitter.as_millis() as u64;
+    interval + Duration::from_millis(rng.gen_range(0..=max_jitter_ms))
+}
+
+/// How long to wait before retrying after `failures` consecutive failed updates: 1s, doubling
+/// with each further failure, up to `cap`.
+fn update_backoff(failures: u32, cap: Duration) -> Duration {
+    let exponent = failures.saturating_sub(1).min(31);
+    Duration::from_secs(1 << exponent).min(cap)
+}
+
+/// What needs rebuilding for the rows built from Releases with `served` fingerprints to reflect
+/// those with `current` ones: only the keys whose fingerprints differ.
+fn plan_rebuild(served: &HashMap<String, u64>, current: &HashMap<String, u64>) -> Rebuild {
+    let changed = changed_keys(served, current);
+    if changed.is_empty() {
+        Rebuild::Nothing
+    } else {
+        Rebuild::Keys(changed)
+    }
+}
+
+/// Swap `rebuilt` (the rows of only `keys`, if given, or of everything) into `mapping`, unless that
+/// would leave `mapping`, which has packages, with none: e.g. every suite was dropped for failing
+/// its signature check, where answering from the old data beats answering nothing.  A key whose
+/// packages have all gone is otherwise emptied like any other change.  Returns whether `rebuilt`
+/// was swapped in.
+fn apply_rebuild(
+    mapping: &mut MadisonMapping,
+    rebuilt: MadisonMapping,
+    keys: Option<&HashSet<String>>,
+) -> bool {
+    let replaces_everything = match keys {
+        Some(keys) => mapping
+            .values()
+            .flat_map(HashMap::keys)
+            .all(|(key, _)| keys.contains(key)),
+        None => true,
+    };
+    if rebuilt.is_empty() && !mapping.is_empty() && replaces_everything {
+        return false;
+    }
+    match keys {
+        Some(keys) => replace_keys(mapping, rebuilt, keys),
+        None => *mapping = rebuilt,
+    }
+    true
+}
+
+/// Bring `system` up to date with `update`, failing over to the `System` from `failover` if that
+/// fails.  While `on_fallback`, the `primary` `System` is tried first, and returned to if it can
+/// be set up, so a mirror outage doesn't pin an instance to its fallbacks.  Returns whether
+/// anything changed, or the update's error if no `System` could be updated.
+async fn update_with_failover<S, UF, PF, FF>(
+    system: &mut Arc<S>,
+    on_fallback: &mut bool,
+    update: impl FnOnce(Arc<S>) -> UF,
+    primary: impl FnOnce() -> PF,
+    failover: impl FnOnce() -> FF,
+) -> Result<bool, anyhow::Error>
+where
+    UF: Future<Output = Result<bool, anyhow::Error>>,
+    PF: Future<Output = Result<S, anyhow::Error>>,
+    FF: Future<Output = Option<S>>,
+{
+    if *on_fallback {
+        info!("Trying the primary mirrors again");
+        match primary().await {
+            Ok(primary) => {
+                info!("Primary mirrors are working again; leaving the fallback mirrors");
+                *system = Arc::new(primary);
+                *on_fallback = false;
+                return Ok(true);
+            }
+            Err(e) => warn!(
+                "Encountered error when updating from the primary mirrors: {}",
+                e
+            ),
+        }
+    }
+    match update(system.clone()).await {
+        Ok(changed) => Ok(changed),
+        Err(e) => {
+            warn!("Encountered error when updating: {}", e);
+            match failover().await {
+                Some(fallback) => {
+                    *system = Arc::new(fallback);
+                    *on_fallback = true;
+                    Ok(true)
+                }
+                None => Err(e),
             }
         }
+    }
+}
+
+/// A fresh `System` using the first of the configured `fallback_mirrors` which can be updated
+/// from, if any.
+async fn failover(config: &MadisonConfig) -> Option<System> {
+    first_working(0..fallback_count(config), |fallback| {
+        info!("Updating from fallback mirrors #{}", fallback + 1);
+        init_system_with_fallback(config, fallback)
+    })
+    .await
+}
+
+/// The result of the first of `attempts` which succeeds, warning about those which fail.
+async fn first_working<A, T, F>(
+    attempts: impl IntoIterator<Item = A>,
+    mut attempt: impl FnMut(A) -> F,
+) -> Option<T>
+where
+    F: Future<Output = Result<T, anyhow::Error>>,
+{
+    for a in attempts {
+        match attempt(a).await {
+            Ok(value) => return Some(value),
+            Err(e) => warn!("Encountered error when updating: {}", e),
+        }
+    }
+    None
+}
+
+/// Notify `refresh` whenever the process receives `SIGHUP`.  The handler is installed before this
+/// returns, so a `SIGHUP` from then on can't terminate the process.
+#[cfg(unix)]
+fn refresh_on_sighup(refresh: Arc<Notify>) -> Result<(), std::io::Error> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::task::spawn(async move {
+        while hangups.recv().await.is_some() {
+            info!("Received SIGHUP: requesting refresh");
+            refresh.notify_one();
+        }
     });
-    info!("Task spawned!");
+    Ok(())
+}
+
+/// The routes and state an instance serves in addition to the defaults, as configured.
+#[derive(Default)]
+struct Features {
+    package_metrics: bool,
+    export: bool,
+    all_report: bool,
+    packages_index: bool,
+    canary: Option<Canary>,
+    history: Option<Arc<RwLock<History>>>,
+    admin_token: Option<String>,
+    metrics: bool,
+}
 
+/// Mount madison-web's routes (including those of `features`) on `rocket`, serving `state`.
+fn mount(
+    rocket: Rocket<Build>,
+    state: MadisonState,
+    metrics: MadisonMetrics,
+    features: Features,
+) -> Rocket<Build> {
+    let instance_name = state.instance_name.clone();
     let mut app = rocket
-        .mount("/", routes![index, madison, madison_html])
-        .manage(MadisonState {
-            madison_mapping: mapping_lock,
-        })
+        .mount(
+            "/",
+            routes![
+                index,
+                style,
+                madison,
+                madison_backend,
+                madison_html,
+                package_page,
+                lookup,
+                arches,
+                suites_for,
+                stats,
+                healthz,
+                readyz,
+                validate_sources
+            ],
+        )
+        .manage(state)
+        .attach(AdHoc::on_response(
+            "Stale data header",
+            |request, response| {
+                Box::pin(async move {
+                    if let Some(state) = request.rocket().state::<MadisonState>() {
+                        if state.stale.load(Ordering::Relaxed) {
+                            response.set_raw_header("X-Madison-Stale", "true");
+                        }
+                    }
+                })
+            },
+        ))
         .attach(Template::try_custom(|engines| {
             let loaded_templates: Vec<_> = engines
                 .tera
@@ -177,13 +1106,1136 @@ pub async fn rocket(key_func: &'static key_func::KeyFunc) -> Rocket<Build> {
             }
             Ok(())
         }));
-    if config.enable_metrics {
+    if features.package_metrics {
+        app = app.mount("/", routes![package_metrics]);
+    }
+    if features.export {
+        app = app.mount("/", routes![export]);
+    }
+    if features.all_report {
+        app = app.mount("/", routes![all_report]);
+    }
+    if let Some(canary) = features.canary {
+        app = app.manage(canary).mount("/", routes![selftest]);
+    }
+    if let Some(history) = features.history {
+        app = app.manage(history).mount("/", routes![version_history]);
+    }
+    if features.packages_index {
+        app = app.mount("/", routes![packages_index]);
+    }
+    if let Some(admin_token) = features.admin_token {
+        app = app
+            .manage(AdminToken(admin_token))
+            .mount("/", routes![effective_config]);
+    }
+    if features.metrics {
         let prometheus = PrometheusMetrics::new();
-        metrics.clone().register_with(&prometheus).unwrap();
+        metrics
+            .clone()
+            .register_with(prometheus.registry())
+            .unwrap();
+        if let Some(instance_name) = &instance_name {
+            metrics
+                .instance_info
+                .with_label_values(&[instance_name])
+                .set(1);
+        }
         app = app
             .attach(prometheus.clone())
+            .attach(AdHoc::on_response(
+                "HTTP response metrics",
+                |request, response| {
+                    Box::pin(async move {
+                        if let Some(metrics) = request.rocket().state::<MadisonMetrics>() {
+                            let route = request
+                                .route()
+                                .and_then(|route| route.name.as_deref())
+                                .unwrap_or("unmatched");
+                            metrics
+                                .http_responses
+                                .with_label_values(&[route, &response.status().code.to_string()])
+                                .inc();
+                        }
+                    })
+                },
+            ))
             .mount("/metrics", prometheus)
             .manage(metrics)
     }
     app
 }
+
+/// Build the madison-web instance, grouping rows with `key_func` unless configuration sets `key`.
+pub async fn rocket(key_func: &'static key_func::KeyFunc) -> Rocket<Build> {
+    let mut rocket = rocket::build();
+    let config: MadisonConfig = rocket.figment().extract().expect("config");
+    let key_func = match &config.key {
+        Some(name) => key_func::by_name(name).expect("unknown key"),
+        None => key_func,
+    };
+    if let Some(address) = &config.bind_address {
+        let address = bind_address(address).expect("bind_address");
+        let figment = rocket.figment().clone().merge(("address", address));
+        rocket = rocket.configure(figment);
+    }
+    let metrics = MadisonMetrics::new().unwrap();
+
+    let system = init_system(&config).await.expect("fapt System init");
+
+    // Notified to request that the sources list and keys are re-read and the mapping rebuilt
+    let refresh = Arc::new(Notify::new());
+    #[cfg(unix)]
+    refresh_on_sighup(refresh.clone()).expect("SIGHUP handler");
+
+    let eol_suites = config.eol_suites.clone();
+    let suite_aliases = config.suite_aliases.clone();
+    let instance_name = config.instance_name.clone();
+    let comparator = config.version_comparator().expect("version_comparator");
+    let regex_limits = RegexLimits {
+        max_length: Some(config.max_regex_length.unwrap_or(DEFAULT_MAX_REGEX_LENGTH)),
+        max_matches: Some(
+            config
+                .max_regex_matches
+                .unwrap_or(DEFAULT_MAX_REGEX_MATCHES),
+        ),
+    };
+    let watched_packages = config.watched_packages.clone();
+    let strip_arch_qualifiers = config.strip_arch_qualifiers;
+    let tracked_arches = config.tracked_arches();
+    let canary = config.selftest_package.clone().map(|package| Canary {
+        package,
+        suite: config.selftest_suite.clone(),
+    });
+    let redacted = redacted_config(&config);
+    let max_request_body_bytes = config
+        .max_request_body_bytes
+        .unwrap_or(DEFAULT_MAX_REQUEST_BODY_BYTES);
+    let max_package_metrics = config
+        .max_package_metrics
+        .unwrap_or(DEFAULT_MAX_PACKAGE_METRICS);
+    let mapping_lock = Arc::new(RwLock::new(HashMap::new()));
+    let c_lock = mapping_lock.clone();
+    let grouped_lock = Arc::new(RwLock::new(HashMap::new()));
+    let c_grouped_lock = grouped_lock.clone();
+    let last_rebuild = Arc::new(RwLock::new(None));
+    let c_last_rebuild = last_rebuild.clone();
+    let stale = Arc::new(AtomicBool::new(false));
+    let c_stale = stale.clone();
+    let expired = Arc::new(RwLock::new(Vec::new()));
+    let c_expired = expired.clone();
+    let history = config.history_db.as_ref().map(|path| {
+        let retention = config
+            .history_retention_days
+            .unwrap_or(DEFAULT_HISTORY_RETENTION_DAYS);
+        Arc::new(RwLock::new(
+            History::open(path, Duration::from_secs(retention * 24 * 60 * 60)).expect("history_db"),
+        ))
+    });
+    let c_history = history.clone();
+    let features = Features {
+        package_metrics: config.enable_package_metrics,
+        export: config.enable_export,
+        all_report: config.enable_all_report,
+        packages_index: config.enable_packages_index,
+        canary,
+        history,
+        admin_token: config.admin_token.clone(),
+        metrics: config.enable_metrics,
+    };
+    let task_metrics = metrics.clone();
+    tokio::task::spawn(async move {
+        let mut system = Arc::new(system);
+        // Whether `system` is using fallback mirrors, after the primary ones failed
+        let mut on_fallback = false;
+        let listing_cache = config.cache_listings.then(ListingCache::new);
+        {
+            // Take the lock immediately for initialisation
+            let mut madison_mapping = c_lock.write().expect("write access failed");
+            let mut grouped_mappings = c_grouped_lock.write().expect("write access failed");
+            info!("Initialising madison mapping");
+            let timer = task_metrics.mapping_build_seconds.start_timer();
+            *madison_mapping =
+                build_madison_mapping_of(&system, key_func, &config, None, listing_cache.as_ref())
+                    .expect("build_madison_mapping");
+            timer.observe_duration();
+            *grouped_mappings =
+                build_grouped_mappings(&system, &config).expect("build_grouped_mappings");
+            *c_expired.write().expect("write access failed") =
+                expired_suites(&system, key_func, &config).expect("expired_suites");
+            let now = SystemTime::now();
+            *c_last_rebuild.write().expect("write access failed") = Some(now);
+            task_metrics.record_rebuild(&madison_mapping, now);
+            record_history(&c_history, &madison_mapping);
+        }
+        let mut fingerprints =
+            release_fingerprints(&system, key_func, &config).unwrap_or_else(|e| {
+                warn!("Failed to fingerprint Release files: {}", e);
+                HashMap::new()
+            });
+        let mut grouped_fingerprints =
+            grouping_fingerprints(&system, &config).unwrap_or_else(|e| {
+                warn!("Failed to fingerprint Release files: {}", e);
+                HashMap::new()
+            });
+
+        let mut rng = StdRng::from_entropy();
+        // Consecutive failed periodic updates, which are retried sooner than usual
+        let mut failures = 0;
+        loop {
+            let interval = config
+                .update_interval_secs
+                .unwrap_or(DEFAULT_UPDATE_INTERVAL_SECS);
+            let jitter = Duration::from_secs(config.update_jitter_secs.unwrap_or(0));
+            let wait = if failures > 0 {
+                let cap = config.max_update_backoff_secs.unwrap_or(interval);
+                update_backoff(failures, Duration::from_secs(cap))
+            } else {
+                update_sleep(Duration::from_secs(interval), jitter, &mut rng)
+            };
+            let periodic_update = async {
+                if interval == 0 {
+                    // Periodic updates are disabled, so only wait for a refresh request
+                    std::future::pending::<()>().await;
+                }
+                sleep(wait).await
+            };
+            let refresh_requested = tokio::select! {
+                _ = periodic_update => false,
+                _ = refresh.notified() => true,
+            };
+            // Whether the update (or reload) succeeded, and whether fapt reports any change
+            let (updated, did_update) = if refresh_requested {
+                // Much of the configuration is also baked into the routes' state (and which routes
+                // are mounted), so rather than apply only part of a new one, none is re-read
+                info!(
+                    "Refresh requested: re-reading the sources list and keys (other \
+                     configuration changes take effect on restart)"
+                );
+                match init_system(&config).await {
+                    Ok(new_system) => {
+                        system = Arc::new(new_system);
+                        on_fallback = false;
+                        (true, true)
+                    }
+                    Err(e) => {
+                        warn!("Encountered error when reloading: {}", e);
+                        c_stale.store(true, Ordering::Relaxed);
+                        (false, false)
+                    }
+                }
+            } else {
+                info!("Checking for updates");
+                task_metrics.update_attempts.inc();
+                let timeout = config.periodic_update_timeout_secs;
+                let update_failures = task_metrics.update_failures.clone();
+                let updated = update_with_failover(
+                    &mut system,
+                    &mut on_fallback,
+                    |system| async move {
+                        update_system(&system, timeout)
+                            .await
+                            .inspect_err(|_| update_failures.inc())
+                    },
+                    || init_system(&config),
+                    || failover(&config),
+                )
+                .await;
+                match updated {
+                    Ok(val) => {
+                        failures = 0;
+                        (true, val)
+                    }
+                    Err(_) => {
+                        failures += 1;
+                        c_stale.store(true, Ordering::Relaxed);
+                        (false, false)
+                    }
+                }
+            };
+            // fapt's report of whether anything changed is cross-checked against the Release
+            // files, so that only suites whose indices actually changed are rebuilt.  The
+            // fingerprints are only kept once the rows built from them are served, so a rebuild
+            // which is discarded is retried
+            let new_fingerprints = release_fingerprints(&system, key_func, &config);
+            let rebuild = match &new_fingerprints {
+                // The sources list may have changed arbitrarily
+                Ok(_) if refresh_requested && did_update => Rebuild::Full,
+                Ok(new_fingerprints) => {
+                    let rebuild = plan_rebuild(&fingerprints, new_fingerprints);
+                    if rebuild == Rebuild::Nothing && did_update {
+                        info!("Update reported, but no Release file changed: not rebuilding");
+                    }
+                    rebuild
+                }
+                Err(e) => {
+                    warn!("Failed to fingerprint Release files: {}", e);
+                    if did_update {
+                        Rebuild::Full
+                    } else {
+                        Rebuild::Nothing
+                    }
+                }
+            };
+            if rebuild == Rebuild::Nothing {
+                // What's served is as current as the archive
+                if updated {
+                    c_stale.store(false, Ordering::Relaxed);
+                }
+            } else {
+                info!("Update happened: updating mapping");
+                let keys = match &rebuild {
+                    Rebuild::Keys(keys) => Some(keys),
+                    _ => None,
+                };
+                let full = keys.is_none();
+                let timer = task_metrics.mapping_build_seconds.start_timer();
+                let rebuilt = build_madison_mapping_of(
+                    &system,
+                    key_func,
+                    &config,
+                    keys,
+                    listing_cache.as_ref(),
+                )
+                .expect("build_madison_mapping");
+                timer.observe_duration();
+                let accepted = apply_rebuild(
+                    &mut c_lock.write().expect("write access failed"),
+                    rebuilt,
+                    keys,
+                );
+                if !accepted {
+                    match keys {
+                        Some(keys) => warn!(
+                            "Rebuilt {} with no packages, and no others are served; keeping \
+                             their previous rows",
+                            keys.iter().cloned().collect::<Vec<_>>().join(", ")
+                        ),
+                        None => warn!("Rebuilt mapping is empty; keeping the previous mapping"),
+                    }
+                    task_metrics.empty_rebuilds.inc();
+                    c_stale.store(true, Ordering::Relaxed);
+                    continue;
+                }
+                if let Ok(new_fingerprints) = new_fingerprints {
+                    fingerprints = new_fingerprints;
+                }
+                c_stale.store(!updated, Ordering::Relaxed);
+                if full {
+                    let new_grouped_mappings =
+                        build_grouped_mappings(&system, &config).expect("build_grouped_mappings");
+                    *c_grouped_lock.write().expect("write access failed") = new_grouped_mappings;
+                    grouped_fingerprints =
+                        grouping_fingerprints(&system, &config).unwrap_or_else(|e| {
+                            warn!("Failed to fingerprint Release files: {}", e);
+                            HashMap::new()
+                        });
+                } else if let Err(e) = update_grouped_mappings(
+                    &system,
+                    &config,
+                    &c_grouped_lock,
+                    &mut grouped_fingerprints,
+                ) {
+                    warn!("Failed to update grouped mappings: {}", e);
+                }
+                *c_expired.write().expect("write access failed") =
+                    expired_suites(&system, key_func, &config).expect("expired_suites");
+                let now = SystemTime::now();
+                *c_last_rebuild.write().expect("write access failed") = Some(now);
+                let madison_mapping = c_lock.read().expect("read access failed");
+                task_metrics.record_rebuild(&madison_mapping, now);
+                record_history(&c_history, &madison_mapping);
+                task_metrics.mapping_rebuilds.inc();
+            }
+        }
+    });
+    info!("Task spawned!");
+
+    let state = MadisonState {
+        madison_mapping: mapping_lock,
+        grouped_mappings: grouped_lock,
+        last_rebuild,
+        stale,
+        expired_suites: expired,
+        eol_suites,
+        suite_aliases,
+        watched_packages,
+        arches: tracked_arches,
+        max_package_metrics,
+        max_request_body_bytes,
+        instance_name,
+        comparator,
+        regex_limits,
+        strip_arch_qualifiers,
+        redacted_config: redacted,
+    };
+    mount(rocket, state, metrics, features)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::tests::{entry, mapping};
+    use crate::version;
+
+    /// State serving `mapping`, as though it had just been built.
+    fn state(mapping: MadisonMapping) -> MadisonState {
+        MadisonState {
+            madison_mapping: Arc::new(RwLock::new(mapping)),
+            grouped_mappings: Default::default(),
+            last_rebuild: Arc::new(RwLock::new(Some(SystemTime::now()))),
+            stale: Default::default(),
+            expired_suites: Default::default(),
+            eol_suites: vec![],
+            suite_aliases: HashMap::new(),
+            watched_packages: HashSet::new(),
+            arches: vec!["amd64".to_string(), "arm64".to_string()],
+            max_package_metrics: DEFAULT_MAX_PACKAGE_METRICS,
+            max_request_body_bytes: DEFAULT_MAX_REQUEST_BODY_BYTES,
+            instance_name: None,
+            comparator: &version::Debian,
+            regex_limits: RegexLimits::default(),
+            strip_arch_qualifiers: true,
+            redacted_config: serde_json::Value::Null,
+        }
+    }
+
+    /// A client of an instance serving `state`, with `features` (and metrics, which the routes
+    /// require) enabled.
+    fn client(state: MadisonState, features: Features) -> Client {
+        let features = Features {
+            metrics: true,
+            ..features
+        };
+        let metrics = MadisonMetrics::new().expect("metrics");
+        Client::tracked(mount(rocket::build(), state, metrics, features)).expect("valid instance")
+    }
+
+    #[test]
+    fn package_metrics_expose_tracked_versions() {
+        let mut state = state(mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("zsh", "bookworm", "5.9-4", entry(&["amd64"])),
+        ]));
+        state.max_package_metrics = 1;
+        let client = client(
+            state,
+            Features {
+                package_metrics: true,
+                ..Default::default()
+            },
+        );
+        let response = client.get("/metrics/packages").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().expect("body");
+        assert!(body.contains(
+            r#"madison_rs_package_version{package="hello",suite="bookworm",version="2.10-3"} 1"#
+        ));
+        // Only the first max_package_metrics samples, by package name
+        assert!(!body.contains("zsh"));
+    }
+
+    #[test]
+    fn export_has_a_line_per_package() {
+        let client = client(
+            state(mapping(vec![
+                ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+                ("hello", "sid", "2.10-5", entry(&["amd64"])),
+                ("zsh", "bookworm", "5.9-4", entry(&["amd64"])),
+                ("bash", "bookworm", "5.2.15-2", entry(&["amd64"])),
+            ])),
+            Features {
+                export: true,
+                ..Default::default()
+            },
+        );
+        let response = client.get("/export").dispatch();
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("application", "x-ndjson"))
+        );
+        let body = response.into_string().expect("body");
+        let lines: Vec<serde_json::Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("each line is JSON"))
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["package"], "bash");
+        assert_eq!(lines[1]["records"].as_array().map(Vec::len), Some(2));
+    }
+
+    #[test]
+    fn encoded_plus_is_part_of_a_package_name() {
+        let client = client(
+            state(mapping(vec![
+                ("libfoo++", "bookworm", "1.0-1", entry(&["amd64"])),
+                ("libfoo", "bookworm", "2.0-1", entry(&["amd64"])),
+            ])),
+            Features::default(),
+        );
+        let response = client.get("/?package=libfoo%2B%2B&text=on").dispatch();
+        assert_eq!(
+            response.into_string().as_deref(),
+            Some("libfoo++ | 1.0-1 | bookworm | amd64\n")
+        );
+        // ... whereas an unencoded `+` is a space, separating package names
+        let response = client
+            .get("/?package=libfoo+libfoo%2B%2B&text=on")
+            .dispatch();
+        let body = response.into_string().expect("body");
+        assert_eq!(body.lines().count(), 2, "{}", body);
+    }
+
+    #[test]
+    fn arches_lists_the_tracked_arches() {
+        let client = client(state(HashMap::new()), Features::default());
+        let response = client.get("/arches").dispatch();
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let arches: Vec<String> =
+            serde_json::from_str(&response.into_string().expect("body")).expect("valid JSON");
+        assert_eq!(arches, ["amd64", "arm64"]);
+    }
+
+    #[test]
+    fn pages_link_the_stylesheet() {
+        let client = client(state(HashMap::new()), Features::default());
+        let response = client.get("/style.css").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::CSS));
+        assert!(response.into_string().expect("body").contains("table"));
+        let index = client.get("/").dispatch().into_string().expect("body");
+        assert!(
+            index.contains(r#"<link rel="stylesheet" href="/style.css">"#),
+            "{}",
+            index
+        );
+    }
+
+    #[test]
+    fn oversized_lookups_are_refused() {
+        let mut state = state(mapping(vec![(
+            "hello",
+            "bookworm",
+            "2.10-3",
+            entry(&["amd64"]),
+        )]));
+        state.max_request_body_bytes = 16;
+        let client = client(state, Features::default());
+        let response = client.post("/lookup").body("hello").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_string().as_deref(),
+            Some("hello | 2.10-3 | bookworm | amd64\n")
+        );
+        let response = client.post("/lookup").body("hello ".repeat(10)).dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+    }
+
+    #[test]
+    fn instance_name_is_shown_and_exported() {
+        let mut state = state(HashMap::new());
+        state.instance_name = Some("staging".to_string());
+        let client = client(state, Features::default());
+        let index = client.get("/").dispatch().into_string().expect("body");
+        assert!(
+            index.contains(r#"<header class="instance">staging</header>"#),
+            "{}",
+            index
+        );
+        let metrics = client
+            .get("/metrics")
+            .dispatch()
+            .into_string()
+            .expect("body");
+        assert!(
+            metrics.contains(r#"madison_rs_instance_info{name="staging"} 1"#),
+            "{}",
+            metrics
+        );
+    }
+
+    #[test]
+    fn root_shows_the_search_form_until_a_package_is_given() {
+        let client = client(
+            state(mapping(vec![(
+                "foo",
+                "bookworm",
+                "1.0-1",
+                entry(&["amd64"]),
+            )])),
+            Features::default(),
+        );
+        for uri in ["/", "/?package=", "/?package=%20", "/?s=bookworm"] {
+            let response = client.get(uri).dispatch();
+            assert_eq!(response.status(), Status::Ok, "{}", uri);
+            let body = response.into_string().expect("body");
+            assert!(body.contains(r#"<form method="get">"#), "{}: {}", uri, body);
+            assert!(!body.contains("<table>"), "{}: {}", uri, body);
+        }
+        let response = client.get("/?package=foo").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().expect("body");
+        assert!(body.contains("<td>foo</td>"), "{}", body);
+        assert!(body.contains("<td>1.0-1</td>"), "{}", body);
+    }
+
+    #[test]
+    fn unchanged_releases_are_not_rebuilt() {
+        let fingerprints = |hashes: &[(&str, u64)]| {
+            crate::key_fingerprints(hashes.iter().map(|(key, hash)| (key.to_string(), *hash)))
+        };
+        let served = fingerprints(&[("bookworm", 1), ("bookworm", 2), ("sid", 3)]);
+        // The same Releases, listed in another order and once per listing
+        let current = fingerprints(&[("sid", 3), ("bookworm", 2), ("bookworm", 1), ("sid", 3)]);
+        assert_eq!(plan_rebuild(&served, &current), Rebuild::Nothing);
+        let current = fingerprints(&[("bookworm", 1), ("bookworm", 2), ("sid", 4)]);
+        assert_eq!(
+            plan_rebuild(&served, &current),
+            Rebuild::Keys(HashSet::from(["sid".to_string()]))
+        );
+    }
+
+    #[test]
+    fn empty_rebuilds_keep_the_previous_mapping() {
+        let previous = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("hello", "sid", "2.10-5", entry(&["amd64"])),
+        ]);
+        let all = HashSet::from(["bookworm".to_string(), "sid".to_string()]);
+        let sid = HashSet::from(["sid".to_string()]);
+        for keys in [None, Some(&all)] {
+            let mut current = previous.clone();
+            assert!(!apply_rebuild(&mut current, HashMap::new(), keys));
+            assert_eq!(current, previous);
+        }
+        // ... while an empty mapping can be rebuilt as empty, and anything else is swapped in
+        let mut current = HashMap::new();
+        assert!(apply_rebuild(&mut current, HashMap::new(), None));
+        let mut current = previous.clone();
+        let rebuilt = mapping(vec![("hello", "sid", "2.10-6", entry(&["amd64"]))]);
+        assert!(apply_rebuild(&mut current, rebuilt, Some(&sid)));
+        assert_eq!(
+            current["hello"].keys().collect::<HashSet<_>>(),
+            HashSet::from([
+                &("bookworm".to_string(), "2.10-3".to_string()),
+                &("sid".to_string(), "2.10-6".to_string())
+            ])
+        );
+    }
+
+    #[test]
+    fn keys_whose_packages_disappeared_are_emptied() {
+        let mut current = mapping(vec![
+            ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+            ("hello", "sid", "2.10-5", entry(&["amd64"])),
+            ("zsh", "sid", "5.9-6", entry(&["amd64"])),
+        ]);
+        let sid = HashSet::from(["sid".to_string()]);
+        assert!(apply_rebuild(&mut current, HashMap::new(), Some(&sid)));
+        assert_eq!(
+            current,
+            mapping(vec![("hello", "bookworm", "2.10-3", entry(&["amd64"]))])
+        );
+        // ... which it keeps being, rather than the rebuild being retried
+        assert!(apply_rebuild(&mut current, HashMap::new(), Some(&sid)));
+    }
+
+    #[test]
+    fn suites_for_lists_the_package_codenames() {
+        let client = client(
+            state(mapping(vec![
+                ("hello", "sid", "2.10-5", entry(&["amd64"])),
+                ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+                ("hello", "bookworm", "2.10-2", entry(&["arm64"])),
+                ("zsh", "trixie", "5.9-6", entry(&["amd64"])),
+            ])),
+            Features::default(),
+        );
+        let response = client.get("/suites-for?package=hello").dispatch();
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        assert_eq!(
+            response.into_string().as_deref(),
+            Some(r#"["bookworm","sid"]"#)
+        );
+        let response = client.get("/suites-for?package=hello&s=sid").dispatch();
+        assert_eq!(response.into_string().as_deref(), Some(r#"["sid"]"#));
+        let response = client.get("/suites-for?package=missing").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn regexes_matching_too_many_packages_are_refused() {
+        let packages: Vec<_> = (0..100).map(|n| format!("package{}", n)).collect();
+        let mut state = state(mapping(
+            packages
+                .iter()
+                .map(|package| (package.as_str(), "bookworm", "1.0-1", entry(&["amd64"])))
+                .collect(),
+        ));
+        state.regex_limits = RegexLimits {
+            max_length: Some(16),
+            max_matches: Some(10),
+        };
+        let client = client(state, Features::default());
+        let response = client.get("/?package=.*&text=on&r=on").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let body = response.into_string().expect("body");
+        assert!(body.contains("please narrow it"), "{}", body);
+        // ... unlike a narrower one
+        let response = client.get("/?package=package1.&text=on&r=on").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().expect("body").lines().count(), 10);
+    }
+
+    #[test]
+    fn config_needs_the_admin_token_and_is_redacted() {
+        let config = MadisonConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..crate::tests::test_config()
+        };
+        let state = MadisonState {
+            redacted_config: redacted_config(&config),
+            ..state(HashMap::new())
+        };
+        let client = client(
+            state,
+            Features {
+                admin_token: config.admin_token.clone(),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            client.get("/config").dispatch().status(),
+            Status::Unauthorized
+        );
+        let response = client
+            .get("/config")
+            .header(Header::new("Authorization", "Bearer guess"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get("/config")
+            .header(Header::new("Authorization", "Bearer s3cret"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().expect("body");
+        assert!(!body.contains("s3cret"), "{}", body);
+        let value: serde_json::Value = serde_json::from_str(&body).expect("valid JSON");
+        assert_eq!(value["admin_token"], "[redacted]");
+        assert_eq!(value["sources_list"], "sources.list");
+    }
+
+    #[test]
+    fn first_page_links_the_next() {
+        let client = client(
+            state(mapping(vec![
+                ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+                ("hello", "trixie", "2.10-3", entry(&["amd64"])),
+                ("hello", "sid", "2.10-5", entry(&["amd64"])),
+                ("zsh", "bookworm", "5.9-4", entry(&["amd64"])),
+            ])),
+            Features::default(),
+        );
+        let page = |uri: &str| client.get(uri).dispatch().into_string().expect("body");
+        // The package column of each row, in order
+        let packages = |body: &str| -> Vec<String> {
+            body.split("<tr>")
+                .skip(1)
+                .filter_map(|row| row.split("<td>").nth(1)?.split_once("</td>"))
+                .map(|(package, _)| package.to_string())
+                .collect()
+        };
+        let first = page("/?package=zsh+hello&per_page=2");
+        assert!(
+            first.contains(
+                r#"<a rel="next" href="?package=zsh%20hello&amp;page=2&amp;per_page=2">"#
+            ),
+            "{}",
+            first
+        );
+        assert!(!first.contains(r#"rel="prev""#), "{}", first);
+        assert_eq!(packages(&first), ["zsh", "hello"]);
+        let second = page("/?package=zsh+hello&per_page=2&page=2");
+        assert!(second.contains(r#"rel="prev""#), "{}", second);
+        assert!(!second.contains(r#"rel="next""#), "{}", second);
+        assert_eq!(packages(&second), ["hello", "hello"]);
+        // Packages are shown in the order they were asked for
+        assert_eq!(
+            packages(&page("/?package=hello+zsh&per_page=2&page=2")),
+            ["hello", "zsh"]
+        );
+    }
+
+    #[test]
+    fn update_sleep_jitter_stays_in_bounds() {
+        let interval = Duration::from_secs(60);
+        let jitter = Duration::from_secs(10);
+        let mut rng = StdRng::seed_from_u64(7);
+        let sleeps: Vec<_> = (0..1000)
+            .map(|_| update_sleep(interval, jitter, &mut rng))
+            .collect();
+        assert!(sleeps
+            .iter()
+            .all(|sleep| *sleep >= interval && *sleep <= interval + jitter));
+        // The jitter actually varies, rather than always being one of the bounds
+        assert!(sleeps
+            .iter()
+            .any(|sleep| *sleep > interval && *sleep < interval + jitter));
+        // Seeded alike, the same sleeps are chosen
+        let mut rng = StdRng::seed_from_u64(7);
+        assert_eq!(update_sleep(interval, jitter, &mut rng), sleeps[0]);
+        let mut rng = StdRng::seed_from_u64(7);
+        assert_eq!(update_sleep(interval, Duration::ZERO, &mut rng), interval);
+    }
+
+    #[test]
+    fn all_report_has_every_package() {
+        let client = client(
+            state(mapping(vec![
+                ("zsh", "bookworm", "5.9-4", entry(&["amd64"])),
+                ("hello", "bookworm", "2.10-3", entry(&["amd64"])),
+                ("hello", "sid", "2.10-5", entry(&["amd64"])),
+                ("bash", "sid", "5.2.21-2", entry(&["amd64"])),
+            ])),
+            Features {
+                all_report: true,
+                ..Default::default()
+            },
+        );
+        let body = client.get("/all").dispatch().into_string().expect("body");
+        let packages: Vec<_> = body
+            .lines()
+            .map(|line| line.split(" | ").next().expect("package column"))
+            .collect();
+        assert_eq!(packages, ["bash", "hello", "hello", "zsh"]);
+    }
+
+    #[test]
+    fn package_page_shows_every_suite() {
+        let client = client(
+            state(mapping(vec![
+                ("firefox", "sid", "120.0-1", entry(&["amd64"])),
+                ("firefox-esr", "bookworm", "115.5.0esr-1", entry(&["amd64"])),
+            ])),
+            Features::default(),
+        );
+        let response = client.get("/package/firefox").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let body = response.into_string().expect("body");
+        assert!(body.contains("<td>120.0-1</td>"), "{}", body);
+        assert!(!body.contains("firefox-esr"), "{}", body);
+        let response = client.get("/package/chromium").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn arch_qualifiers_filter_to_the_architecture() {
+        let client = client(
+            state(mapping(vec![
+                ("foo", "bookworm", "1.0-1", entry(&["amd64", "arm64"])),
+                ("foo", "sid", "1.1-1", entry(&["amd64"])),
+            ])),
+            Features::default(),
+        );
+        let response = client.get("/?package=foo:arm64&text=on").dispatch();
+        assert_eq!(
+            response.into_string().as_deref(),
+            Some("foo | 1.0-1 | bookworm | arm64\n")
+        );
+        // `:any` only strips the qualifier
+        let response = client.get("/?package=foo:any&text=on").dispatch();
+        assert_eq!(response.into_string().expect("body").lines().count(), 2);
+    }
+
+    #[test]
+    fn selftest_needs_the_canary() {
+        let selftest = |package: &str, suite: Option<&str>| {
+            let client = client(
+                state(mapping(vec![(
+                    "hello",
+                    "bookworm",
+                    "2.10-3",
+                    entry(&["amd64"]),
+                )])),
+                Features {
+                    canary: Some(Canary {
+                        package: package.to_string(),
+                        suite: suite.map(str::to_string),
+                    }),
+                    ..Default::default()
+                },
+            );
+            let response = client.get("/selftest").dispatch();
+            (response.status(), response.into_string().expect("body"))
+        };
+        assert_eq!(
+            selftest("hello", Some("bookworm")),
+            (
+                Status::Ok,
+                "hello | 2.10-3 | bookworm | amd64\n".to_string()
+            )
+        );
+        let (status, body) = selftest("hello", Some("sid"));
+        assert_eq!(status, Status::InternalServerError);
+        assert!(body.contains("canary package hello not found"), "{}", body);
+        assert_eq!(selftest("zsh", None).0, Status::InternalServerError);
+    }
+
+    #[test]
+    fn by_selects_a_grouping() {
+        let state = state(mapping(vec![(
+            "hello",
+            "bookworm",
+            "2.10-3",
+            entry(&["amd64"]),
+        )]));
+        state
+            .grouped_mappings
+            .write()
+            .expect("write access failed")
+            .insert(
+                "component".to_string(),
+                mapping(vec![("hello", "main", "2.10-3", entry(&["amd64"]))]),
+            );
+        let client = client(state, Features::default());
+        let response = client
+            .get("/?package=hello&text=on&by=component")
+            .dispatch();
+        assert_eq!(
+            response.into_string().as_deref(),
+            Some("hello | 2.10-3 | main | amd64\n")
+        );
+        let response = client.get("/?package=hello&text=on&by=nonsense").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn stale_data_is_flagged() {
+        let state = state(mapping(vec![(
+            "hello",
+            "bookworm",
+            "2.10-3",
+            entry(&["amd64"]),
+        )]));
+        let stale = state.stale.clone();
+        let client = client(state, Features::default());
+        let stale_header = || {
+            client
+                .get("/?package=hello&text=on")
+                .dispatch()
+                .headers()
+                .get_one("X-Madison-Stale")
+                .map(str::to_string)
+        };
+        assert_eq!(stale_header(), None);
+        // As the update loop does when an update fails
+        stale.store(true, Ordering::Relaxed);
+        assert_eq!(stale_header().as_deref(), Some("true"));
+    }
+
+    #[test]
+    fn responses_are_counted_by_route_and_status() {
+        let client = client(
+            state(mapping(vec![(
+                "hello",
+                "bookworm",
+                "2.10-3",
+                entry(&["amd64"]),
+            )])),
+            Features::default(),
+        );
+        client.get("/?package=hello&text=on").dispatch();
+        client.get("/?package=hello&text=on").dispatch();
+        let response = client.get("/?package=%20&text=on").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let metrics = client
+            .rocket()
+            .state::<MadisonMetrics>()
+            .expect("metrics are managed");
+        let count = |status: &str| {
+            metrics
+                .http_responses
+                .with_label_values(&["madison", status])
+                .get()
+        };
+        assert_eq!((count("200"), count("400")), (2, 1));
+    }
+
+    #[test]
+    fn if_modified_since_gets_not_modified() {
+        let state = state(mapping(vec![(
+            "hello",
+            "bookworm",
+            "2.10-3",
+            entry(&["amd64"]),
+        )]));
+        let last_rebuild = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        *state.last_rebuild.write().expect("write access failed") = Some(last_rebuild);
+        let client = client(state, Features::default());
+        let get = |since: SystemTime| {
+            client
+                .get("/?package=hello&text=on")
+                .header(Header::new(
+                    "If-Modified-Since",
+                    httpdate::fmt_http_date(since),
+                ))
+                .dispatch()
+        };
+        assert_eq!(get(last_rebuild).status(), Status::NotModified);
+        let response = get(last_rebuild - Duration::from_secs(1));
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.headers().get_one("Last-Modified"),
+            Some(httpdate::fmt_http_date(last_rebuild).as_str())
+        );
+    }
+
+    #[test]
+    fn bind_address_accepts_ipv6_loopback() {
+        for address in ["::1", "[::1]"] {
+            let address = bind_address(address).expect("valid address");
+            // Binding to an ephemeral port proves the address is usable, not just parseable
+            let listener = std::net::TcpListener::bind((address, 0)).expect("bind ::1");
+            let port = listener.local_addr().expect("local address").port();
+            std::net::TcpStream::connect((address, port)).expect("connect over IPv6");
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sighup_requests_refresh() {
+        let refresh = Arc::new(Notify::new());
+        refresh_on_sighup(refresh.clone()).expect("SIGHUP handler");
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .expect("run kill");
+        assert!(status.success());
+        tokio::time::timeout(Duration::from_secs(5), refresh.notified())
+            .await
+            .expect("SIGHUP requests a refresh");
+    }
+
+    #[tokio::test]
+    async fn failover_uses_the_first_mirror_which_works() {
+        let mut tried = vec![];
+        let working = first_working(["primary", "secondary", "tertiary"], |mirror| {
+            tried.push(mirror);
+            async move {
+                match mirror {
+                    "primary" => Err(anyhow::anyhow!("{} is down", mirror)),
+                    _ => Ok(mirror),
+                }
+            }
+        })
+        .await;
+        assert_eq!(working, Some("secondary"));
+        assert_eq!(tried, ["primary", "secondary"]);
+        assert_eq!(
+            first_working(["primary"], |mirror| async move {
+                Err::<(), _>(anyhow::anyhow!("{} is down", mirror))
+            })
+            .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn updates_fail_over_and_return_to_the_primary() {
+        async fn down<T>() -> Result<T, anyhow::Error> {
+            Err(anyhow::anyhow!("mirror is down"))
+        }
+        let fresh = |name: &'static str| move || async move { Ok(name) };
+        let mut system = Arc::new("primary");
+        let mut on_fallback = false;
+
+        // The primary fails, so the fallback is used...
+        let changed = update_with_failover(
+            &mut system,
+            &mut on_fallback,
+            |_| down(),
+            || async { unreachable!("the primary is only retried from a fallback") },
+            || async { Some("fallback") },
+        )
+        .await;
+        assert!(changed.expect("failed over"));
+        assert_eq!((*system, on_fallback), ("fallback", true));
+
+        // ... and updated while the primary is still down...
+        let changed = update_with_failover(
+            &mut system,
+            &mut on_fallback,
+            |system| async move {
+                assert_eq!(*system, "fallback");
+                Ok(false)
+            },
+            down::<&str>,
+            || async { unreachable!("the fallback is working") },
+        )
+        .await;
+        assert!(!changed.expect("updated the fallback"));
+        assert_eq!((*system, on_fallback), ("fallback", true));
+
+        // ... until the primary works again
+        let changed = update_with_failover(
+            &mut system,
+            &mut on_fallback,
+            |_| async { unreachable!("the primary is set up afresh") },
+            fresh("primary"),
+            || async { unreachable!("the primary is working") },
+        )
+        .await;
+        assert!(changed.expect("returned to the primary"));
+        assert_eq!((*system, on_fallback), ("primary", false));
+
+        // With no working fallback, the update fails
+        let changed = update_with_failover(
+            &mut system,
+            &mut on_fallback,
+            |_| down(),
+            fresh("primary"),
+            || async { None },
+        )
+        .await;
+        assert!(changed.is_err());
+        assert_eq!((*system, on_fallback), ("primary", false));
+    }
+
+    #[test]
+    fn stats_report_expired_suites() {
+        let config = MadisonConfig {
+            arches: vec!["amd64".to_string()],
+            ..crate::tests::test_config()
+        };
+        let sources = crate::tests::expiring_sources("expiring-stats");
+        let (mapping, expired) = tokio::runtime::Runtime::new()
+            .expect("runtime")
+            .block_on(async {
+                let system = crate::init_system_from(&config, &sources, &[]).await?;
+                let mapping = build_madison_mapping(&system, &key_func::codename, &config)?;
+                let expired = expired_suites(&system, &key_func::codename, &config)?;
+                Ok::<_, anyhow::Error>((mapping, expired))
+            })
+            .expect("fixture mapping");
+        let state = MadisonState {
+            expired_suites: Arc::new(RwLock::new(expired)),
+            ..state(mapping)
+        };
+        let client = client(state, Features::default());
+        let stats: serde_json::Value =
+            serde_json::from_str(&client.get("/stats").dispatch().into_string().expect("body"))
+                .expect("valid JSON");
+        assert_eq!(stats["expired_suites"], serde_json::json!(["bookworm"]));
+        assert_eq!(stats["package_count"], 1);
+    }
+
+    #[test]
+    fn bind_address_rejects_garbage() {
+        assert!(bind_address("[::1").is_err());
+        assert!(bind_address("localhost").is_err());
+    }
+}
@@ -0,0 +1,36 @@
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+
+/// The configured `admin_token`, as managed state.
+pub(super) struct AdminToken(pub(super) String);
+
+/// A request guard for requests carrying the admin token, as `Authorization: Bearer <token>`.
+pub(super) struct Admin;
+
+/// Compare without short-circuiting, so response timing doesn't reveal how much of a guess was
+/// right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let token = match request.rocket().state::<AdminToken>() {
+            Some(token) => token,
+            None => return request::Outcome::Error((Status::NotFound, ())),
+        };
+        match request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            Some(given) if constant_time_eq(given.as_bytes(), token.0.as_bytes()) => {
+                request::Outcome::Success(Admin)
+            }
+            _ => request::Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
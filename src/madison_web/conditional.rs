@@ -0,0 +1,72 @@
+use std::convert::Infallible;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+
+/// The parsed `If-Modified-Since` header of a request, if it had a valid one.
+pub(super) struct IfModifiedSince(Option<SystemTime>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfModifiedSince {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(IfModifiedSince(
+            request
+                .headers()
+                .get_one("If-Modified-Since")
+                .and_then(|value| httpdate::parse_http_date(value).ok()),
+        ))
+    }
+}
+
+/// A response carrying `Last-Modified`, which becomes a bodyless 304 if the client's copy is
+/// still current.
+pub(super) struct Conditional<R> {
+    last_modified: Option<SystemTime>,
+    not_modified: bool,
+    body: R,
+}
+
+impl<R> Conditional<R> {
+    pub(super) fn new(
+        last_modified: Option<SystemTime>,
+        if_modified_since: &IfModifiedSince,
+        body: R,
+    ) -> Self {
+        // HTTP dates only have second precision
+        let last_modified = last_modified.map(|time| {
+            UNIX_EPOCH
+                + Duration::from_secs(
+                    time.duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0),
+                )
+        });
+        let not_modified = match (last_modified, if_modified_since.0) {
+            (Some(last_modified), Some(since)) => last_modified <= since,
+            _ => false,
+        };
+        Conditional {
+            last_modified,
+            not_modified,
+            body,
+        }
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Conditional<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = if self.not_modified {
+            Response::build().status(Status::NotModified).finalize()
+        } else {
+            self.body.respond_to(request)?
+        };
+        if let Some(last_modified) = self.last_modified {
+            response.set_raw_header("Last-Modified", httpdate::fmt_http_date(last_modified));
+        }
+        Ok(response)
+    }
+}
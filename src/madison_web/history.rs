@@ -0,0 +1,212 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::MadisonMapping;
+
+/// When a version of a package was first seen in a suite.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(super) struct Observation {
+    pub(super) package: String,
+    pub(super) suite: String,
+    pub(super) version: String,
+    /// Seconds since the Unix epoch
+    pub(super) timestamp: u64,
+}
+
+/// A rolling record of the versions each (re)build of the mapping found, kept in `history_db`.
+///
+/// The file has a tab-separated `timestamp package suite version` line per observation, and only
+/// gains a line when a version appears in a suite it wasn't in at the previous rebuild, so it
+/// stays compact however often the mapping is rebuilt.  Observations older than the retention
+/// period are dropped as new ones are recorded.
+pub(super) struct History {
+    path: PathBuf,
+    retention: Duration,
+    observations: Vec<Observation>,
+    /// The (package, suite, version)s in the mapping at the last rebuild
+    current: HashSet<(String, String, String)>,
+}
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0)
+}
+
+impl Observation {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let timestamp = fields.next()?.parse().ok()?;
+        let (package, suite, version) = (fields.next()?, fields.next()?, fields.next()?);
+        Some(Observation {
+            package: package.to_string(),
+            suite: suite.to_string(),
+            version: version.to_string(),
+            timestamp,
+        })
+    }
+
+    fn line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\n",
+            self.timestamp, self.package, self.suite, self.version
+        )
+    }
+}
+
+impl History {
+    /// Load the history at `path`, which is created on the first rebuild if it doesn't exist.
+    pub(super) fn open(path: &str, retention: Duration) -> Result<Self, anyhow::Error> {
+        let observations = match File::open(path) {
+            Ok(file) => {
+                let mut observations = vec![];
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    match Observation::parse(&line) {
+                        Some(observation) => observations.push(observation),
+                        None => warn!("Skipping unparseable history line {:?}", line),
+                    }
+                }
+                observations
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        // Without knowing what has since disappeared, assume everything recorded is still
+        // present, so a restart doesn't record it all again
+        let current = observations
+            .iter()
+            .map(|o| (o.package.clone(), o.suite.clone(), o.version.clone()))
+            .collect();
+        Ok(History {
+            path: path.into(),
+            retention,
+            observations,
+            current,
+        })
+    }
+
+    /// Record the versions in `mapping` which weren't in the previous one, as seen at `now`.
+    pub(super) fn record(
+        &mut self,
+        mapping: &MadisonMapping,
+        now: SystemTime,
+    ) -> Result<(), anyhow::Error> {
+        let timestamp = epoch_secs(now);
+        let current: HashSet<_> = mapping
+            .iter()
+            .flat_map(|(package, entries)| {
+                entries
+                    .keys()
+                    .map(move |(suite, version)| (package.clone(), suite.clone(), version.clone()))
+            })
+            .collect();
+        let mut new: Vec<_> = current
+            .difference(&self.current)
+            .map(|(package, suite, version)| Observation {
+                package: package.clone(),
+                suite: suite.clone(),
+                version: version.clone(),
+                timestamp,
+            })
+            .collect();
+        new.sort_by(|o1, o2| (&o1.package, &o1.suite).cmp(&(&o2.package, &o2.suite)));
+        self.current = current;
+
+        let cutoff = epoch_secs(
+            now.checked_sub(self.retention)
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+        );
+        let before = self.observations.len();
+        self.observations.retain(|o| o.timestamp >= cutoff);
+        let pruned = self.observations.len() != before;
+        self.observations.extend(new.iter().cloned());
+        if pruned {
+            // Rewrite the file without the expired lines, replacing it only once complete
+            let tmp_path = self.path.with_extension("tmp");
+            let mut file = File::create(&tmp_path)?;
+            for observation in &self.observations {
+                file.write_all(observation.line().as_bytes())?;
+            }
+            file.sync_all()?;
+            fs::rename(&tmp_path, &self.path)?;
+        } else if !new.is_empty() {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            for observation in &new {
+                file.write_all(observation.line().as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The observations of `package`, oldest first, in `suites` if given.
+    pub(super) fn query(&self, package: &str, suites: Option<&[String]>) -> Vec<&Observation> {
+        self.observations
+            .iter()
+            .filter(|o| o.package == package)
+            .filter(|o| {
+                suites
+                    .map(|suites| suites.iter().any(|s| s.eq_ignore_ascii_case(&o.suite)))
+                    .unwrap_or(true)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{entry, mapping, temp_file};
+
+    #[test]
+    fn observations_are_recorded_and_read_back() {
+        let path = temp_file("history.tsv", b"");
+        let retention = Duration::from_secs(7 * 24 * 60 * 60);
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let mut history = History::open(&path, retention).expect("open history");
+        let bookworm = ("hello", "bookworm", "2.10-3", entry(&["amd64"]));
+        history
+            .record(&mapping(vec![bookworm.clone()]), at(1000))
+            .expect("record");
+        // Only the new version is recorded; bookworm's is unchanged
+        let sid = ("hello", "sid", "2.10-5", entry(&["amd64"]));
+        history
+            .record(&mapping(vec![bookworm, sid]), at(2000))
+            .expect("record");
+        let observation = |suite: &str, version: &str, timestamp| Observation {
+            package: "hello".to_string(),
+            suite: suite.to_string(),
+            version: version.to_string(),
+            timestamp,
+        };
+        let expected = [
+            observation("bookworm", "2.10-3", 1000),
+            observation("sid", "2.10-5", 2000),
+        ];
+        assert_eq!(
+            history.query("hello", None),
+            expected.iter().collect::<Vec<_>>()
+        );
+
+        let reopened = History::open(&path, retention).expect("reopen history");
+        assert_eq!(
+            reopened.query("hello", None),
+            expected.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            reopened.query("hello", Some(&["SID".to_string()])),
+            [&expected[1]]
+        );
+        assert!(reopened.query("zsh", None).is_empty());
+    }
+}
@@ -16,13 +16,26 @@ const PACKAGE_TABLE: &str = r#"
           <th></th>
           <th>Architecture</th>
         </thead>
-      {% for _, package_records in madison %}
-        {% for record in package_records %}
+      {% for package in madison %}
+        {% for record in package.1 %}
           {{ package_macros::package_row(record=record) }}
         {% endfor %}
       {% endfor %}
       </table>
     "#;
+const PAGINATION: &str = r#"
+      {% if prev_page or next_page %}
+      <nav class="pagination">
+        {% if prev_page %}
+        <a rel="prev" href="?package={{ query.package | urlencode }}{% if query.s %}&amp;s={{ query.s | urlencode }}{% endif %}{% if query.a %}&amp;a={{ query.a | urlencode }}{% endif %}{% if query.by %}&amp;by={{ query.by | urlencode }}{% endif %}&amp;page={{ prev_page }}&amp;per_page={{ per_page }}">Previous</a>
+        {% endif %}
+        <span>Page {{ page }} ({{ total }} records)</span>
+        {% if next_page %}
+        <a rel="next" href="?package={{ query.package | urlencode }}{% if query.s %}&amp;s={{ query.s | urlencode }}{% endif %}{% if query.a %}&amp;a={{ query.a | urlencode }}{% endif %}{% if query.by %}&amp;by={{ query.by | urlencode }}{% endif %}&amp;page={{ next_page }}&amp;per_page={{ per_page }}">Next</a>
+        {% endif %}
+      </nav>
+      {% endif %}
+    "#;
 const SEARCH_FORM: &str = r#"
       <form method="get">
         <input id="urlInput" type="search" name="package" placeholder="package name" autofocus required>
@@ -30,8 +43,59 @@ const SEARCH_FORM: &str = r#"
       </form>
     "#;
 
+const STYLE_CSS: &str = r#"
+body {
+  font-family: sans-serif;
+  margin: 1em auto;
+  max-width: 60em;
+  padding: 0 1em;
+}
+table {
+  border-collapse: collapse;
+  width: 100%;
+}
+th, td {
+  border: 1px solid #ccc;
+  padding: 0.25em 0.5em;
+  text-align: left;
+}
+tbody tr:nth-child(even), table > tr:nth-child(even) {
+  background-color: #f4f4f4;
+}
+.instance {
+  color: #666;
+  font-weight: bold;
+  margin-bottom: 1em;
+}
+.pagination {
+  display: flex;
+  gap: 1em;
+  margin-top: 1em;
+}
+.stale {
+  background-color: #fff3cd;
+  border: 1px solid #e0c36c;
+  padding: 0.5em;
+}
+@media (max-width: 40em) {
+  th, td {
+    display: block;
+  }
+}
+"#;
+
 const BASE_TMPL: &str = r#"
         <html>
+          <head>
+            <meta name="viewport" content="width=device-width, initial-scale=1">
+            <link rel="stylesheet" href="/style.css">
+          </head>
+          {% if instance_name %}
+          <header class="instance">{{ instance_name }}</header>
+          {% endif %}
+          {% if stale %}
+          <p class="stale">The most recent archive update failed: this data may be out of date.</p>
+          {% endif %}
           {% block body %}{% endblock %}
           {% include "footer" ignore missing %}
         </html>
@@ -43,12 +107,14 @@ const INDEX_TMPL: &str = r#"
 const PACKAGE_TMPL: &str = r#"
         {% extends "base.html" %}
         {% import "package-macros" as package_macros %}
-        {% block body %}{% include "package-table" %}{% endblock %}
+        {% block body %}{% include "package-table" %}{% include "pagination" %}{% endblock %}
     "#;
 pub(super) const TEMPLATES: &[(&str, &str)] = &[
     ("package-macros", PACKAGE_MACROS),
     ("package-table", PACKAGE_TABLE),
+    ("pagination", PAGINATION),
     ("search-form", SEARCH_FORM),
+    ("style.css", STYLE_CSS),
     ("base.html", BASE_TMPL),
     ("index.html", INDEX_TMPL),
     ("package.html", PACKAGE_TMPL),
@@ -0,0 +1,103 @@
+use std::time::SystemTime;
+
+use rocket_prometheus::prometheus::{
+    opts, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Registry,
+};
+
+use crate::MadisonMapping;
+
+/// The metrics madison-rs records, shared between the web server and the CLI.
+#[derive(Clone)]
+pub(crate) struct MadisonMetrics {
+    pub(crate) update_attempts: IntCounter,
+    pub(crate) update_failures: IntCounter,
+    pub(crate) package_lookups: IntCounterVec,
+    pub(crate) mapping_rebuilds: IntCounter,
+    pub(crate) empty_rebuilds: IntCounter,
+    pub(crate) http_responses: IntCounterVec,
+    pub(crate) instance_info: IntGaugeVec,
+    pub(crate) mapping_packages: IntGauge,
+    pub(crate) last_rebuild: IntGauge,
+    pub(crate) mapping_build_seconds: Histogram,
+}
+
+impl MadisonMetrics {
+    pub(crate) fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            update_attempts: IntCounter::new(
+                "madison_rs_apt_update_attempts",
+                "Count of system apt update attempts",
+            )?,
+            update_failures: IntCounter::new(
+                "madison_rs_apt_update_failures",
+                "Count of failed system apt update attempts",
+            )?,
+            package_lookups: IntCounterVec::new(
+                opts!(
+                    "madison_rs_package_lookups",
+                    "Count of packages looked up, and how"
+                ),
+                &["route", "package_name"],
+            )?,
+            mapping_rebuilds: IntCounter::new(
+                "madison_rs_mapping_rebuilds",
+                "Count of rebuilds of the internal madison mapping",
+            )?,
+            empty_rebuilds: IntCounter::new(
+                "madison_rs_empty_mapping_rebuilds",
+                "Count of rebuilds discarded for producing an empty mapping",
+            )?,
+            http_responses: IntCounterVec::new(
+                opts!(
+                    "madison_rs_http_responses_total",
+                    "Count of HTTP responses, by route and status code"
+                ),
+                &["route", "status"],
+            )?,
+            instance_info: IntGaugeVec::new(
+                opts!(
+                    "madison_rs_instance_info",
+                    "Always 1, labelled with the configured instance name"
+                ),
+                &["name"],
+            )?,
+            mapping_packages: IntGauge::new(
+                "madison_rs_mapping_packages",
+                "Number of packages in the current madison mapping",
+            )?,
+            last_rebuild: IntGauge::new(
+                "madison_rs_last_rebuild_timestamp_seconds",
+                "Unix timestamp of the last successful (re)build of the madison mapping",
+            )?,
+            mapping_build_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "madison_rs_mapping_build_seconds",
+                    "Time taken to build (or partially rebuild) the madison mapping",
+                )
+                .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0]),
+            )?,
+        })
+    }
+
+    /// Record that `mapping` is the current mapping, as (re)built at `at`.
+    pub(crate) fn record_rebuild(&self, mapping: &MadisonMapping, at: SystemTime) {
+        self.mapping_packages.set(mapping.len() as i64);
+        if let Ok(since_epoch) = at.duration_since(SystemTime::UNIX_EPOCH) {
+            self.last_rebuild.set(since_epoch.as_secs() as i64);
+        }
+    }
+
+    pub(crate) fn register_with(self, registry: &Registry) -> Result<(), anyhow::Error> {
+        registry.register(Box::new(self.update_attempts))?;
+        registry.register(Box::new(self.update_failures))?;
+        registry.register(Box::new(self.package_lookups))?;
+        registry.register(Box::new(self.mapping_rebuilds))?;
+        registry.register(Box::new(self.empty_rebuilds))?;
+        registry.register(Box::new(self.http_responses))?;
+        registry.register(Box::new(self.instance_info))?;
+        registry.register(Box::new(self.mapping_packages))?;
+        registry.register(Box::new(self.last_rebuild))?;
+        registry.register(Box::new(self.mapping_build_seconds))?;
+        Ok(())
+    }
+}